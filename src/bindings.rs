@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs;
+
+use minifb::Key;
+
+use crate::keys::Button;
+
+// Maps keyboard keys and gamepad buttons/axes to Game Boy buttons and emulator hotkeys.
+//
+// Bindings can be loaded from a file with one binding per line:
+//
+//   key.S = a
+//   key.Tab = fast-forward
+//   pad.East = a
+//   axis.LeftStickX- = left
+//   key.P = none
+//
+// Key names are the minifb::Key names, pad and axis names are the gilrs ones. Bindings in the
+// file override the defaults for the same input, `none` removes the binding.
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Input {
+    Key(Key),
+    #[cfg(feature = "gamepad")]
+    PadButton(gilrs::Button),
+    // axis and whether it's the positive direction
+    #[cfg(feature = "gamepad")]
+    PadAxis(gilrs::Axis, bool),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    Debugger,
+    FastForward,
+    Screenshot,
+    SaveState,
+    LoadState,
+    PreviousSlot,
+    NextSlot,
+    Rewind,
+    SlowMotion,
+    FrameAdvance,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Action {
+    Button(Button),
+    Hotkey(Hotkey),
+}
+
+impl TryFrom<&str> for Action {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "a" => Ok(Action::Button(Button::A)),
+            "b" => Ok(Action::Button(Button::B)),
+            "select" => Ok(Action::Button(Button::Select)),
+            "start" => Ok(Action::Button(Button::Start)),
+            "right" => Ok(Action::Button(Button::Right)),
+            "left" => Ok(Action::Button(Button::Left)),
+            "up" => Ok(Action::Button(Button::Up)),
+            "down" => Ok(Action::Button(Button::Down)),
+            "quit" => Ok(Action::Hotkey(Hotkey::Quit)),
+            "pause" => Ok(Action::Hotkey(Hotkey::Pause)),
+            "debugger" => Ok(Action::Hotkey(Hotkey::Debugger)),
+            "fast-forward" => Ok(Action::Hotkey(Hotkey::FastForward)),
+            "screenshot" => Ok(Action::Hotkey(Hotkey::Screenshot)),
+            "save-state" => Ok(Action::Hotkey(Hotkey::SaveState)),
+            "load-state" => Ok(Action::Hotkey(Hotkey::LoadState)),
+            "previous-slot" => Ok(Action::Hotkey(Hotkey::PreviousSlot)),
+            "next-slot" => Ok(Action::Hotkey(Hotkey::NextSlot)),
+            "rewind" => Ok(Action::Hotkey(Hotkey::Rewind)),
+            "slow-motion" => Ok(Action::Hotkey(Hotkey::SlowMotion)),
+            "frame-advance" => Ok(Action::Hotkey(Hotkey::FrameAdvance)),
+            _ => Err(format!("unknown action {}", value)),
+        }
+    }
+}
+
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7,
+    Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+    Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+    Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10,
+    Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket,
+    Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert,
+    Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5,
+    Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash,
+    Key::NumPadAsterisk, Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+fn parse_key(name: &str) -> Option<Key> {
+    KEYS.iter().find(|k| format!("{:?}", k) == name).copied()
+}
+
+#[cfg(feature = "gamepad")]
+const PAD_BUTTONS: [gilrs::Button; 19] = [
+    gilrs::Button::South, gilrs::Button::East, gilrs::Button::North, gilrs::Button::West,
+    gilrs::Button::C, gilrs::Button::Z,
+    gilrs::Button::LeftTrigger, gilrs::Button::LeftTrigger2,
+    gilrs::Button::RightTrigger, gilrs::Button::RightTrigger2,
+    gilrs::Button::Select, gilrs::Button::Start, gilrs::Button::Mode,
+    gilrs::Button::LeftThumb, gilrs::Button::RightThumb,
+    gilrs::Button::DPadUp, gilrs::Button::DPadDown, gilrs::Button::DPadLeft,
+    gilrs::Button::DPadRight,
+];
+
+#[cfg(feature = "gamepad")]
+const PAD_AXES: [gilrs::Axis; 8] = [
+    gilrs::Axis::LeftStickX, gilrs::Axis::LeftStickY, gilrs::Axis::LeftZ,
+    gilrs::Axis::RightStickX, gilrs::Axis::RightStickY, gilrs::Axis::RightZ,
+    gilrs::Axis::DPadX, gilrs::Axis::DPadY,
+];
+
+#[cfg(feature = "gamepad")]
+fn parse_input(kind: &str, name: &str) -> Result<Input, String> {
+    match kind {
+        "key" => parse_key(name).map(Input::Key),
+        "pad" => PAD_BUTTONS.iter()
+            .find(|b| format!("{:?}", b) == name)
+            .map(|b| Input::PadButton(*b)),
+        "axis" => {
+            let (axis, positive) = match name.strip_suffix('+') {
+                Some(axis) => (axis, true),
+                None => (name.strip_suffix('-').unwrap_or(name), false),
+            };
+            PAD_AXES.iter()
+                .find(|a| format!("{:?}", a) == axis)
+                .map(|a| Input::PadAxis(*a, positive))
+        },
+        _ => return Err(format!("unknown input type {}", kind)),
+    }.ok_or(format!("unknown {} {}", kind, name))
+}
+
+#[cfg(not(feature = "gamepad"))]
+fn parse_input(kind: &str, name: &str) -> Result<Input, String> {
+    match kind {
+        "key" => parse_key(name).map(Input::Key).ok_or(format!("unknown key {}", name)),
+        "pad" | "axis" => Err(String::from("gamepad support isn't enabled")),
+        _ => Err(format!("unknown input type {}", kind)),
+    }
+}
+
+pub struct Bindings {
+    inputs: HashMap<Input, Action>,
+}
+
+impl Bindings {
+    pub fn new() -> Bindings {
+        let mut bindings = Bindings {
+            inputs: HashMap::new(),
+        };
+
+        let keys = [
+            (Key::S, Action::Button(Button::A)),
+            (Key::A, Action::Button(Button::B)),
+            (Key::G, Action::Button(Button::Start)),
+            (Key::H, Action::Button(Button::Select)),
+            (Key::Up, Action::Button(Button::Up)),
+            (Key::Down, Action::Button(Button::Down)),
+            (Key::Left, Action::Button(Button::Left)),
+            (Key::Right, Action::Button(Button::Right)),
+            (Key::Escape, Action::Hotkey(Hotkey::Quit)),
+            (Key::Space, Action::Hotkey(Hotkey::Debugger)),
+            (Key::P, Action::Hotkey(Hotkey::Pause)),
+            (Key::Tab, Action::Hotkey(Hotkey::FastForward)),
+            (Key::F12, Action::Hotkey(Hotkey::Screenshot)),
+            (Key::F5, Action::Hotkey(Hotkey::SaveState)),
+            (Key::F6, Action::Hotkey(Hotkey::PreviousSlot)),
+            (Key::F7, Action::Hotkey(Hotkey::NextSlot)),
+            (Key::F8, Action::Hotkey(Hotkey::LoadState)),
+            (Key::Backspace, Action::Hotkey(Hotkey::Rewind)),
+            (Key::LeftShift, Action::Hotkey(Hotkey::SlowMotion)),
+            (Key::N, Action::Hotkey(Hotkey::FrameAdvance)),
+        ];
+        for (key, action) in keys {
+            bindings.inputs.insert(Input::Key(key), action);
+        }
+
+        #[cfg(feature = "gamepad")]
+        {
+            // Nintendo layout: A is the right face button
+            let buttons = [
+                (gilrs::Button::East, Action::Button(Button::A)),
+                (gilrs::Button::South, Action::Button(Button::B)),
+                (gilrs::Button::Start, Action::Button(Button::Start)),
+                (gilrs::Button::Select, Action::Button(Button::Select)),
+                (gilrs::Button::DPadUp, Action::Button(Button::Up)),
+                (gilrs::Button::DPadDown, Action::Button(Button::Down)),
+                (gilrs::Button::DPadLeft, Action::Button(Button::Left)),
+                (gilrs::Button::DPadRight, Action::Button(Button::Right)),
+                (gilrs::Button::RightTrigger, Action::Hotkey(Hotkey::FastForward)),
+                (gilrs::Button::LeftTrigger, Action::Hotkey(Hotkey::Rewind)),
+            ];
+            for (button, action) in buttons {
+                bindings.inputs.insert(Input::PadButton(button), action);
+            }
+
+            let axes = [
+                (gilrs::Axis::LeftStickX, true, Action::Button(Button::Right)),
+                (gilrs::Axis::LeftStickX, false, Action::Button(Button::Left)),
+                (gilrs::Axis::LeftStickY, true, Action::Button(Button::Up)),
+                (gilrs::Axis::LeftStickY, false, Action::Button(Button::Down)),
+            ];
+            for (axis, positive, action) in axes {
+                bindings.inputs.insert(Input::PadAxis(axis, positive), action);
+            }
+        }
+
+        bindings
+    }
+
+    pub fn from_file(path: &str) -> Result<Bindings, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut bindings = Bindings::new();
+        for (i, line) in contents.lines().enumerate() {
+            bindings.parse_line(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        }
+
+        Ok(bindings)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let (input, action) = match line.split_once('=') {
+            Some((input, action)) => (input.trim(), action.trim()),
+            None => return Err(String::from("expected INPUT = ACTION")),
+        };
+
+        let (kind, name) = match input.split_once('.') {
+            Some(split) => split,
+            None => return Err(format!("bad input {}", input)),
+        };
+
+        let input = parse_input(kind, name)?;
+
+        if action == "none" {
+            self.inputs.remove(&input);
+        } else {
+            self.inputs.insert(input, Action::try_from(action)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn action(&self, input: &Input) -> Option<Action> {
+        self.inputs.get(input).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_bindings_override_defaults() {
+        let mut bindings = Bindings::new();
+
+        bindings.parse_line("key.Z = a").unwrap();
+        bindings.parse_line("key.S = none").unwrap();
+        bindings.parse_line("# comment").unwrap();
+
+        assert_eq!(bindings.action(&Input::Key(Key::Z)), Some(Action::Button(Button::A)));
+        assert_eq!(bindings.action(&Input::Key(Key::S)), None);
+        assert!(bindings.parse_line("key.Nope = a").is_err());
+        assert!(bindings.parse_line("key.Z = jump").is_err());
+    }
+}
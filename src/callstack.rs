@@ -0,0 +1,170 @@
+use crate::registers::Registers;
+
+// Shadow call stack for gbdb, kept from the CALL/RST/RET/RETI instructions that run and the
+// interrupts that are serviced. Games sometimes drop return addresses (pop + jp) or reset SP, so
+// frames are matched to the real stack by their SP instead of trusting every return to pair up
+// with a call.
+
+// Deeper than any sane game, keeps runaway recursion from growing it forever
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // where the call or the interrupt happened
+    pub call_site: u16,
+    pub target: u16,
+    pub return_address: u16,
+    // SP after pushing the return address
+    pub sp: u16,
+}
+
+#[derive(Clone)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC)
+}
+
+fn is_rst(opcode: u8) -> bool {
+    opcode & 0xC7 == 0xC7
+}
+
+fn is_ret(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+// CALL and RST, step over runs until they return
+pub fn is_subroutine_call(opcode: u8) -> bool {
+    is_call(opcode) || is_rst(opcode)
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+        }
+    }
+
+    // Innermost frame last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    fn push(&mut self, frame: Frame) {
+        // frames at or below the new return address were left without returning
+        while let Some(last) = self.frames.last() {
+            if last.sp > frame.sp {
+                break;
+            }
+            self.frames.pop();
+        }
+
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // After a return SP is above the return addresses that are gone
+    fn returned(&mut self, sp: u16) {
+        while let Some(last) = self.frames.last() {
+            if last.sp >= sp {
+                break;
+            }
+            self.frames.pop();
+        }
+    }
+
+    // Called after every instruction with the opcode, PC and SP it started with
+    pub fn instruction(&mut self, opcode: u8, pc: u16, sp: u16, reg: &Registers) {
+        if is_subroutine_call(opcode) && reg.sp == sp.wrapping_sub(2) {
+            let (kind, length) = if is_call(opcode) { (FrameKind::Call, 3) } else { (FrameKind::Rst, 1) };
+
+            self.push(Frame {
+                kind,
+                call_site: pc,
+                target: reg.pc,
+                return_address: pc.wrapping_add(length),
+                sp: reg.sp,
+            });
+        } else if is_ret(opcode) && reg.sp == sp.wrapping_add(2) {
+            self.returned(reg.sp);
+        }
+    }
+
+    // pc is the interrupted instruction
+    pub fn interrupt(&mut self, pc: u16, vector: u16, sp: u16) {
+        self.push(Frame {
+            kind: FrameKind::Interrupt,
+            call_site: pc,
+            target: vector,
+            return_address: pc,
+            sp,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(stack: &CallStack) -> Vec<u16> {
+        stack.frames().iter().map(|f| f.target).collect()
+    }
+
+    #[test]
+    fn follows_calls_and_returns() {
+        let mut stack = CallStack::new();
+        let mut reg = Registers::new();
+
+        // call $0200 at $0150
+        reg.sp = 0xFFFC;
+        reg.pc = 0x0200;
+        stack.instruction(0xCD, 0x0150, 0xFFFE, &reg);
+        // rst $38 at $0205
+        reg.sp = 0xFFFA;
+        reg.pc = 0x0038;
+        stack.instruction(0xFF, 0x0205, 0xFFFC, &reg);
+        stack.interrupt(0x0038, 0x0040, 0xFFF8);
+        assert_eq!(targets(&stack), [0x0200, 0x0038, 0x0040]);
+        assert_eq!(stack.frames()[1].return_address, 0x0206);
+
+        // reti
+        reg.sp = 0xFFFA;
+        reg.pc = 0x0038;
+        stack.instruction(0xD9, 0x0045, 0xFFF8, &reg);
+        assert_eq!(targets(&stack), [0x0200, 0x0038]);
+
+        // a not taken ret z leaves SP alone
+        stack.instruction(0xC8, 0x0039, 0xFFFA, &reg);
+        assert_eq!(targets(&stack), [0x0200, 0x0038]);
+
+        // the rst handler drops its return address and jumps back, the next call replaces it
+        reg.sp = 0xFFFA;
+        reg.pc = 0x0300;
+        stack.instruction(0xCD, 0x0210, 0xFFFC, &reg);
+        assert_eq!(targets(&stack), [0x0200, 0x0300]);
+
+        // ret from both
+        reg.sp = 0xFFFC;
+        stack.instruction(0xC9, 0x0301, 0xFFFA, &reg);
+        assert_eq!(targets(&stack), [0x0200]);
+        reg.sp = 0xFFFE;
+        stack.instruction(0xC9, 0x0213, 0xFFFC, &reg);
+        assert!(stack.frames().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use parse_int::parse;
+
+// Event breakpoints for gbdb, set with catch. The CPU notes the events as they happen and gbdb
+// stops after the instruction (or interrupt dispatch) that caused them, except for illegal
+// opcodes which it stops at before they run.
+
+const INTERRUPTS: [(&str, u16); 5] = [
+    ("vblank", 0x40),
+    ("stat", 0x48),
+    ("timer", 0x50),
+    ("serial", 0x58),
+    ("joypad", 0x60),
+];
+
+fn interrupt_name(vector: u16) -> &'static str {
+    INTERRUPTS.iter()
+        .find(|(_, v)| *v == vector)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Event {
+    // vector
+    Interrupt(u16),
+    VBlank,
+    // LY changed to this
+    Scanline(u8),
+    BankSwitch { from: u8, to: u8 },
+    // source address
+    Dma(u16),
+    Halt,
+    Stop,
+    IllegalOpcode(u8),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Interrupt(vector) => write!(f, "{} interrupt ({:#04x})", interrupt_name(*vector), vector),
+            Event::VBlank => write!(f, "VBlank"),
+            Event::Scanline(ly) => write!(f, "LY == {}", ly),
+            Event::BankSwitch { from, to } => write!(f, "ROM bank switch {:#04x} -> {:#04x}", from, to),
+            Event::Dma(source) => write!(f, "OAM DMA from {:#06x}", source),
+            Event::Halt => write!(f, "HALT"),
+            Event::Stop => write!(f, "STOP"),
+            Event::IllegalOpcode(opcode) => write!(f, "illegal opcode {:#04x}", opcode),
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Catchpoint {
+    // any interrupt if there's no vector
+    Interrupt(Option<u16>),
+    VBlank,
+    Scanline(u8),
+    BankSwitch,
+    Dma,
+    Halt,
+    Stop,
+    IllegalOpcode,
+}
+
+impl Catchpoint {
+    pub fn matches(&self, event: Event) -> bool {
+        match (*self, event) {
+            (Catchpoint::Interrupt(None), Event::Interrupt(_)) => true,
+            (Catchpoint::Interrupt(Some(v)), Event::Interrupt(vector)) => v == vector,
+            (Catchpoint::VBlank, Event::VBlank) => true,
+            (Catchpoint::Scanline(l), Event::Scanline(ly)) => l == ly,
+            (Catchpoint::BankSwitch, Event::BankSwitch { .. }) => true,
+            (Catchpoint::Dma, Event::Dma(_)) => true,
+            (Catchpoint::Halt, Event::Halt) => true,
+            (Catchpoint::Stop, Event::Stop) => true,
+            (Catchpoint::IllegalOpcode, Event::IllegalOpcode(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+// catch's arguments, e.g. ["interrupt", "timer"] or ["ly", "144"]
+impl TryFrom<&[&str]> for Catchpoint {
+    type Error = String;
+
+    fn try_from(args: &[&str]) -> Result<Self, Self::Error> {
+        match args {
+            ["interrupt"] => Ok(Catchpoint::Interrupt(None)),
+            ["interrupt", name] => match INTERRUPTS.iter().find(|(n, _)| n == name) {
+                Some((_, vector)) => Ok(Catchpoint::Interrupt(Some(*vector))),
+                None => match parse::<u16>(name) {
+                    Ok(vector) if INTERRUPTS.iter().any(|(_, v)| *v == vector) => {
+                        Ok(Catchpoint::Interrupt(Some(vector)))
+                    },
+                    _ => Err(format!("unknown interrupt {}", name)),
+                },
+            },
+            ["vblank"] => Ok(Catchpoint::VBlank),
+            ["ly", line] => match parse::<u8>(line) {
+                Ok(ly) if ly <= 153 => Ok(Catchpoint::Scanline(ly)),
+                _ => Err(format!("bad scanline {}", line)),
+            },
+            ["bank"] => Ok(Catchpoint::BankSwitch),
+            ["dma"] => Ok(Catchpoint::Dma),
+            ["halt"] => Ok(Catchpoint::Halt),
+            ["stop"] => Ok(Catchpoint::Stop),
+            ["illegal"] => Ok(Catchpoint::IllegalOpcode),
+            _ => Err(String::from("usage: catch interrupt [NAME|VECTOR] | vblank | ly N | bank | dma | halt | stop | illegal")),
+        }
+    }
+}
+
+impl fmt::Display for Catchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Catchpoint::Interrupt(None) => write!(f, "interrupt"),
+            Catchpoint::Interrupt(Some(vector)) => write!(f, "interrupt {}", interrupt_name(*vector)),
+            Catchpoint::VBlank => write!(f, "vblank"),
+            Catchpoint::Scanline(ly) => write!(f, "ly {}", ly),
+            Catchpoint::BankSwitch => write!(f, "bank"),
+            Catchpoint::Dma => write!(f, "dma"),
+            Catchpoint::Halt => write!(f, "halt"),
+            Catchpoint::Stop => write!(f, "stop"),
+            Catchpoint::IllegalOpcode => write!(f, "illegal"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_matches_events() {
+        let timer = Catchpoint::try_from(&["interrupt", "timer"][..]).unwrap();
+        assert!(timer.matches(Event::Interrupt(0x50)));
+        assert!(!timer.matches(Event::Interrupt(0x40)));
+        assert_eq!(Catchpoint::try_from(&["interrupt", "0x50"][..]), Ok(timer));
+        assert!(Catchpoint::try_from(&["interrupt"][..]).unwrap().matches(Event::Interrupt(0x40)));
+
+        let ly = Catchpoint::try_from(&["ly", "144"][..]).unwrap();
+        assert!(ly.matches(Event::Scanline(144)));
+        assert!(!ly.matches(Event::Scanline(143)));
+        assert!(!ly.matches(Event::VBlank));
+
+        assert!(Catchpoint::try_from(&["ly", "200"][..]).is_err());
+        assert!(Catchpoint::try_from(&["interrupt", "0x42"][..]).is_err());
+        assert!(Catchpoint::try_from(&["nope"][..]).is_err());
+    }
+}
@@ -1,22 +1,60 @@
-use std::fs::File;
+use std::fs::{self,File};
 
 use crate::registers::{Flag,Registers};
 use crate::memory_bus::MemoryBus;
 use crate::gpu::GPUInterrupts;
 
 use crate::debug;
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+use crate::trace::Tracer;
+use crate::symbols::Symbols;
+use crate::callstack::{self,CallStack};
+use crate::disassembler::disassemble;
+use crate::gdbstub::GdbStub;
+use crate::script::{Hook,Hooks};
+use crate::history::{self,History};
+use crate::catchpoint::{Catchpoint,Event};
+
+const ONE_FRAME_IN_CYCLES: usize = 70224;
 
 pub struct CPU {
     pub reg: Registers,
     pub memory_bus: MemoryBus,
     pub counter: i32,
-    pub breakpoints: Vec<u16>,
+    pub breakpoints: Vec<debug::Breakpoint>,
+    // shown every time gbdb stops
+    pub displays: Vec<debug::Display>,
+    pub catchpoints: Vec<Catchpoint>,
+    // the ones that happened during this step that aren't noticed at the end of it
+    events: Vec<Event>,
     pub clock: Clock,
+    pub rom_path: String,
+    // frames emulated so far
+    pub frames: u64,
+    pub tracer: Option<Tracer>,
+    pub symbols: Symbols,
+    pub call_stack: CallStack,
+    // instructions run so far, reverse execution goes back to a number of them
+    pub instructions: u64,
+    pub history: History,
+    // reverse execution is running again what already ran, nothing stops
+    pub replaying: bool,
+    // takes over from gbdb when a gdb is connected
+    pub gdb: Option<GdbStub>,
+    // set while a script runs, which then gets the breakpoints and watchpoints instead of gbdb
+    pub hooks: Option<Hooks>,
+    // how many gbdb source commands are running inside one another
+    pub sourcing: usize,
+    // a movie is recording or replaying, loading a state would make it desync
+    pub movie_active: bool,
 
     is_halted: bool,
+    is_stopped: bool,
     ime: bool,
     debug: bool,
     stepping: bool,
+    // (PC, SP) to stop at for next and finish
+    run_until: Option<(u16, u16)>,
     pub stop_at_next_frame: bool,
 }
 
@@ -66,6 +104,7 @@ pub enum Opcode {
 
     DI,
     HALT,
+    STOP,
     EI,
 
     PREFIX,
@@ -254,7 +293,7 @@ impl TryFrom<u8> for Opcode {
             0x0d => Ok(Opcode::DEC(IncDecTarget::C)),
             0x0e => Ok(Opcode::LD(LDType::Byte(LDTarget::C, LDSource::D8))),
             0x0f => Ok(Opcode::RRCA),
-            // 0x10 STOP. Not used by commercial games.
+            0x10 => Ok(Opcode::STOP),
             0x11 => Ok(Opcode::LD(LDType::Word(LDWordTarget::DE))),
             0x12 => Ok(Opcode::LD(LDType::IndirectFromA(Indirect::DEIndirect))),
             0x13 => Ok(Opcode::INC(IncDecTarget::DE)),
@@ -755,24 +794,46 @@ impl TryFrom<u8> for PrefixedOpcode {
 
 impl CPU {
     pub fn new(rom_path: String, boot_rom_path: Option<String>, debug: bool) -> CPU {
+        let rom = fs::read(&rom_path).expect("can't read ROM");
+
+        CPU::with_rom(rom_path, rom, boot_rom_path, debug)
+    }
+
+    // rom_path is still where the things that go next to the ROM go, like save states
+    fn with_rom(rom_path: String, rom: Vec<u8>, boot_rom_path: Option<String>, debug: bool) -> CPU {
         let mut cpu = CPU {
             reg: Registers::new(),
             counter: 20,
             memory_bus: MemoryBus::new(),
             breakpoints: vec![],
+            displays: vec![],
+            catchpoints: vec![],
+            events: vec![],
             clock: Clock {
                 m: 0,
                 t: 0,
             },
             ime: true,
+            rom_path,
+            frames: 0,
+            tracer: None,
+            symbols: Symbols::new(),
+            call_stack: CallStack::new(),
+            instructions: 0,
+            history: History::new(),
+            replaying: false,
+            gdb: None,
+            hooks: None,
+            sourcing: 0,
+            movie_active: false,
             is_halted: false,
+            is_stopped: false,
             debug: debug,
             stepping: false,
+            run_until: None,
             stop_at_next_frame: false,
         };
 
-        cpu.breakpoints.push(0x100);
-
         // TODO error handling
 
         match boot_rom_path {
@@ -785,11 +846,25 @@ impl CPU {
             },
             None => {}
         };
-        cpu.memory_bus.read_rom(&rom_path).expect("can't read ROM");
+        cpu.memory_bus.load_rom(rom);
 
         cpu
     }
 
+    // A CPU running rom, padded with zeros to 32 KiB. The ROM isn't written anywhere, but it gets
+    // a path of its own so tests don't share what's saved next to it.
+    #[cfg(test)]
+    pub fn from_test_rom(rom: &[u8]) -> CPU {
+        use std::sync::atomic::{AtomicUsize,Ordering};
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut data = rom.to_vec();
+        data.resize(data.len().max(0x8000), 0);
+        let name = format!("gbemu-rs-test-{}-{}.gb", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+
+        CPU::with_rom(std::env::temp_dir().join(name).to_string_lossy().into(), data, None, false)
+    }
+
    #[inline(always)]
     fn log_debug(&self, message: String) {
         if self.debug {
@@ -819,7 +894,7 @@ impl CPU {
     }
 
     fn execute(&mut self) -> u8 {
-        if self.is_halted {
+        if self.is_halted || self.is_stopped {
             return 1;
         }
 
@@ -1820,6 +1895,15 @@ impl CPU {
                 self.reg.pc += 1;
             },
 
+            Opcode::STOP => {
+                // only a joypad input wakes the CPU up, see step()
+                self.is_stopped = true;
+                self.memory_bus.clock.div = 0;
+
+                // STOP is followed by a (usually 0x00) byte that's skipped
+                self.reg.pc += 2;
+            },
+
             Opcode::PUSH(target) => {
                 self.reg.sp -= 1;
 
@@ -2913,28 +2997,46 @@ impl CPU {
     pub fn step(&mut self) -> usize {
         self.log_debug(format!("emulating..."));
 
-        if self.debug && (self.breakpoints.contains(&self.reg.pc)
-        | self.stepping) {
-            let r = debug::drop_to_shell(self);
-            match r {
-                Ok(ret) => match ret {
-                    debug::DebuggerRet::Step => {
-                        self.stepping = true;
-                    },
-                    debug::DebuggerRet::Frame => {
-                        self.stepping = false;
-                        self.stop_at_next_frame = true;
-                    }
-                    _ => {
-                        self.stepping = false;
-                        self.stop_at_next_frame = false;
-                    }
-                }
-                Err(_) => panic!("error dropping to shell!"),
-            }
+        let pc = self.reg.pc;
+        if self.replaying {
+            // going over what already ran for reverse execution
+        } else if self.gdb.as_mut().is_some_and(|gdb| gdb.should_stop(pc)) {
+            self.stop_for_gdb();
+        } else if self.debug && self.hooks.is_none() && (self.stepping || self.reached_run_until() || debug::check_breakpoints(self) || self.caught_illegal_opcode(pc)) {
+            self.drop_to_shell();
+        }
+
+        // while halted or stopped nothing runs, so there's no instruction to trace
+        if let Some(tracer) = self.tracer.as_mut().filter(|_| !self.replaying && !self.is_halted && !self.is_stopped) {
+            tracer.trace(&self.reg, &self.memory_bus, &self.symbols, self.frames);
         }
 
+        // (LY, ROM bank, halted, stopped) to compare with after the step
+        let before = self.catching().then(|| {
+            self.memory_bus.take_dma_started();
+            (self.memory_bus.peek(0xFF44), self.memory_bus.memory.bank(0x4000), self.is_halted, self.is_stopped)
+        });
+
+        let sp = self.reg.sp;
+        let opcode = self.memory_bus.peek(pc);
         let mut cycles = self.execute() as u32;
+
+        if self.debug {
+            self.call_stack.instruction(opcode, pc, sp, &self.reg);
+        }
+
+        // when replaying hits are left for reverse execution to find
+        let hit = if self.replaying { None } else { self.memory_bus.take_watch_hit() };
+        if let Some(hit) = hit {
+            if let Some(gdb) = &mut self.gdb {
+                gdb.watch_hit(hit);
+            } else if let Some(hooks) = &mut self.hooks {
+                hooks.hits.push_back(Hook::Watch(hit));
+            } else if self.debug {
+                println!("watchpoint: {} by instruction at {:#06x}", hit, pc);
+                self.stepping = true;
+            }
+        }
         let mut cycles_t = cycles as u32 * 4;
 
         self.clock.m += cycles as u32;
@@ -2951,6 +3053,7 @@ impl CPU {
 
         if vblank {
             self.memory_bus.interrupt_flag.vblank = true;
+            self.frames += 1;
         }
 
         if lcd_stat {
@@ -2965,10 +3068,19 @@ impl CPU {
             self.memory_bus.interrupt_flag.timer = true;
         }
 
+        if self.memory_bus.serial.step(cycles_t) {
+            self.memory_bus.interrupt_flag.serial = true;
+        }
+
         if self.has_interrupt() {
             self.is_halted = false;
         }
 
+        // STOP is left on joypad input even if the interrupt isn't enabled
+        if self.memory_bus.interrupt_flag.joypad {
+            self.is_stopped = false;
+        }
+
         let mut interrupted = false;
         if self.ime {
             let ie = self.memory_bus.interrupt_enable;
@@ -3000,7 +3112,8 @@ impl CPU {
                 iflag.joypad = false;
             }
 
-            self.memory_bus.write_byte(0xFF0F, iflag.into());
+            // not through write_byte so watchpoints only see what the game does
+            self.memory_bus.interrupt_flag = iflag;
         }
 
         if interrupted {
@@ -3013,10 +3126,60 @@ impl CPU {
         self.clock.m += cycles as u32;
         self.clock.t += (cycles as u32) * 4;
 
+        self.instructions += 1;
+
+        if let Some(before) = before {
+            self.catch_events(before, vblank);
+        }
+
+        // a halted CPU would hit the same breakpoint over and over
+        if let Some(hooks) = &mut self.hooks {
+            let next = self.reg.pc;
+            if hooks.breakpoints.contains(&next) && (next != pc || !self.is_halted) {
+                hooks.hits.push_back(Hook::Breakpoint(next));
+            }
+        }
+
         cycles_t as usize
     }
 
+    // Runs the CPU until the PPU enters VBlank and returns true. With the LCD off there's no
+    // VBlank and a frame is just one frame worth of cycles. Returns false early if a script hook
+    // fired.
+    pub fn run_frame(&mut self) -> bool {
+        let mut cycles = 0;
+
+        if self.debug {
+            history::checkpoint(self);
+        }
+
+        // with the LCD on only VBlank ends it, a cap would cut frames short when they run a bit long
+        while self.memory_bus.gpu.lcd_enabled() || cycles < ONE_FRAME_IN_CYCLES {
+            cycles += self.step();
+
+            if self.memory_bus.gpu.frame_complete {
+                self.memory_bus.gpu.frame_complete = false;
+                return true;
+            }
+
+            if self.hooks.as_ref().is_some_and(|hooks| !hooks.hits.is_empty()) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn halted(&self) -> bool {
+        self.is_halted
+    }
+
     pub fn handle_interrupt(&mut self, address: u16) {
+        let pc = self.reg.pc;
         self.reg.sp -= 1;
         self.memory_bus.write_byte(self.reg.sp, (self.reg.pc >> 8) as u8);
         self.reg.sp -= 1;
@@ -3026,25 +3189,176 @@ impl CPU {
 
         self.reg.pc = address;
 
+        if self.debug {
+            self.call_stack.interrupt(pc, address, self.reg.sp);
+        }
+        if self.catching() {
+            self.events.push(Event::Interrupt(address));
+        }
+    }
+
+    fn catching(&self) -> bool {
+        self.debug && !self.replaying && self.hooks.is_none() && !self.catchpoints.is_empty()
+    }
+
+    // Stops at the next instruction if something this step did set off a catchpoint
+    fn catch_events(&mut self, (ly, bank, halted, stopped): (u8, u8, bool, bool), vblank: bool) {
+        let mut events = std::mem::take(&mut self.events);
+
+        if vblank {
+            events.push(Event::VBlank);
+        }
+        let new_ly = self.memory_bus.peek(0xFF44);
+        if new_ly != ly {
+            events.push(Event::Scanline(new_ly));
+        }
+        let new_bank = self.memory_bus.memory.bank(0x4000);
+        if new_bank != bank {
+            events.push(Event::BankSwitch { from: bank, to: new_bank });
+        }
+        if self.memory_bus.take_dma_started() {
+            events.push(Event::Dma((self.memory_bus.dma as u16) << 8));
+        }
+        if self.is_halted && !halted {
+            events.push(Event::Halt);
+        }
+        if self.is_stopped && !stopped {
+            events.push(Event::Stop);
+        }
+
+        for event in events {
+            if self.catchpoints.iter().any(|c| c.matches(event)) {
+                println!("caught {}", event);
+                self.stepping = true;
+            }
+        }
+    }
+
+    // Illegal opcodes are caught before they run, the emulator can't run them
+    fn caught_illegal_opcode(&self, pc: u16) -> bool {
+        if !self.catching() || self.is_halted || self.is_stopped {
+            return false;
+        }
+
+        let opcode = self.memory_bus.peek(pc);
+        let event = Event::IllegalOpcode(opcode);
+        if Opcode::try_from(opcode).is_ok() || !self.catchpoints.iter().any(|c| c.matches(event)) {
+            return false;
+        }
+
+        println!("caught {} at {:#06x}", event, pc);
+        true
     }
 
     pub fn drop_to_shell(&mut self) {
         let r = debug::drop_to_shell(self);
+        history::checkpoint(self);
         match r {
-            Ok(ret) => match ret {
-                debug::DebuggerRet::Step => {
-                    self.stepping = true;
-                },
-                debug::DebuggerRet::Frame => {
-                    self.stepping = false;
-                    self.stop_at_next_frame = true;
-                }
-                _ => {
+            Ok(ret) => self.resume(ret),
+            Err(_) => panic!("error dropping to shell!"),
+        }
+    }
+
+    // Carries on as a gbdb command says
+    pub fn resume(&mut self, ret: debug::DebuggerRet) {
+        self.run_until = None;
+        match ret {
+            debug::DebuggerRet::Step => {
+                self.stepping = true;
+            },
+            debug::DebuggerRet::Next => {
+                let opcode = self.memory_bus.peek(self.reg.pc);
+                if callstack::is_subroutine_call(opcode) {
+                    let length = disassemble(&self.memory_bus, &self.symbols, self.reg.pc).length;
+                    self.run_until = Some((self.reg.pc.wrapping_add(length), self.reg.sp));
                     self.stepping = false;
-                    self.stop_at_next_frame = false;
+                } else {
+                    self.stepping = true;
                 }
+            },
+            debug::DebuggerRet::Finish => {
+                // gbdb only returns this with a frame to finish
+                let frame = self.call_stack.frames().last().expect("no frame to finish");
+                self.run_until = Some((frame.return_address, frame.sp.wrapping_add(2)));
+                self.stepping = false;
+            },
+            debug::DebuggerRet::Frame => {
+                self.stepping = false;
+                self.stop_at_next_frame = true;
             }
-            Err(_) => panic!("error dropping to shell!"),
+            _ => {
+                self.stepping = false;
+                self.stop_at_next_frame = false;
+            }
+        }
+    }
+
+    fn stop_for_gdb(&mut self) {
+        if let Some(mut gdb) = self.gdb.take() {
+            gdb.stop(self);
+            if !gdb.detached {
+                self.gdb = Some(gdb);
+            }
+        }
+    }
+
+    // Whether a step over or step out is done
+    fn reached_run_until(&mut self) -> bool {
+        match self.run_until {
+            // SP tells a return apart from a recursive call reaching the same address
+            Some((pc, sp)) if self.reg.pc == pc && self.reg.sp >= sp => {
+                self.run_until = None;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+// Registers and the memory bus are saved as their own chunks
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.ime);
+        w.bool(self.is_halted);
+        w.bool(self.is_stopped);
+        w.u32(self.clock.m);
+        w.u32(self.clock.t);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ime = r.bool()?;
+        self.is_halted = r.bool()?;
+        self.is_stopped = r.bool()?;
+        self.clock.m = r.u32()?;
+        self.clock.t = r.u32()?;
+
+        // the stack it followed and the way here are gone
+        self.call_stack.clear();
+        self.history.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_with_the_lcd_on_take_one_frame_of_cycles() {
+        // ld a, $91; ldh [$40], a; inc bc; jr -3
+        let mut rom = vec![0; 0x100];
+        rom.extend_from_slice(&[0x3E, 0x91, 0xE0, 0x40, 0x03, 0x18, 0xFD]);
+        let mut cpu = CPU::from_test_rom(&rom);
+
+        // the first one starts wherever the LCD was turned on
+        assert!(cpu.run_frame());
+        for _ in 0..4 {
+            let bc = cpu.reg.bc();
+            assert!(cpu.run_frame());
+            // 20 cycles a loop
+            let cycles = cpu.reg.bc().wrapping_sub(bc) as usize * 20;
+            assert!(cycles.abs_diff(ONE_FRAME_IN_CYCLES) <= 20, "frame took {} cycles", cycles);
         }
     }
 }
@@ -1,19 +1,37 @@
+use std::{fmt,fs};
+
 use rustyline::error::ReadlineError;
-use rustyline::{CompletionType, Config, EditMode, Editor};
+use rustyline::completion::{Completer,Pair};
+use rustyline::{CompletionType, Config, Context, EditMode, Editor};
+use rustyline_derive::{Helper,Highlighter,Hinter,Validator};
 use std::process;
 use parse_int::parse;
 
 use crate::cpu::CPU;
-use crate::cpu::Opcode;
+use crate::disassembler::disassemble;
+use crate::watchpoint::{Watchpoint,WatchKind};
+use crate::expr::{self,Expr};
+use crate::callstack::FrameKind;
 use crate::registers::Flag;
+use crate::save_state;
+use crate::script;
+use crate::history;
+use crate::catchpoint::Catchpoint;
+use crate::symbols;
+use crate::session;
 
 #[derive(Debug)]
 pub enum DebuggerRet {
     Step,
+    // step over calls
+    Next,
+    // run until the current function returns
+    Finish,
     Continue,
     Frame,
 }
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum Registers {
     A,
     B,
@@ -47,6 +65,160 @@ impl TryFrom<&str> for Registers {
     }
 }
 
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Registers::A => "a",
+            Registers::B => "b",
+            Registers::C => "c",
+            Registers::D => "d",
+            Registers::E => "e",
+            Registers::F => "f",
+            Registers::H => "h",
+            Registers::L => "l",
+            Registers::SP => "sp",
+            Registers::PC => "pc",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn get_register(cpu: &CPU, register: Registers) -> u16 {
+    match register {
+        Registers::A => cpu.reg.a as u16,
+        Registers::B => cpu.reg.b as u16,
+        Registers::C => cpu.reg.c as u16,
+        Registers::D => cpu.reg.d as u16,
+        Registers::E => cpu.reg.e as u16,
+        Registers::F => cpu.reg.f as u16,
+        Registers::H => cpu.reg.h as u16,
+        Registers::L => cpu.reg.l as u16,
+        Registers::SP => cpu.reg.sp,
+        Registers::PC => cpu.reg.pc,
+    }
+}
+
+pub fn set_register(cpu: &mut CPU, register: Registers, val: u16) -> Result<(), String> {
+    let byte = || u8::try_from(val).map_err(|_| format!("{:#x} doesn't fit in {}", val, register));
+
+    match register {
+        Registers::A => cpu.reg.a = byte()?,
+        Registers::B => cpu.reg.b = byte()?,
+        Registers::C => cpu.reg.c = byte()?,
+        Registers::D => cpu.reg.d = byte()?,
+        Registers::E => cpu.reg.e = byte()?,
+        // the low nibble of F always reads 0
+        Registers::F => cpu.reg.f = byte()? & 0xF0,
+        Registers::H => cpu.reg.h = byte()?,
+        Registers::L => cpu.reg.l = byte()?,
+        Registers::SP => cpu.reg.sp = val,
+        Registers::PC => cpu.reg.pc = val,
+    }
+
+    Ok(())
+}
+
+// Something shown every time gbdb stops
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Display {
+    Register(Registers),
+    Memory(u16),
+}
+
+impl Display {
+    fn show(&self, cpu: &CPU) -> String {
+        match self {
+            Display::Register(r @ (Registers::SP | Registers::PC)) => format!("{} = {:#06x}", r, get_register(cpu, *r)),
+            Display::Register(r) => format!("{} = {:#04x}", r, get_register(cpu, *r)),
+            Display::Memory(address) => format!("[{:#06x}] = {:#04x}", address, cpu.memory_bus.peek(*address)),
+        }
+    }
+}
+
+fn print_displays(cpu: &CPU) {
+    for (i, display) in cpu.displays.iter().enumerate() {
+        println!("{}: {}", i, display.show(cpu));
+    }
+}
+
+// info lcd|timer|int|mbc|oam
+fn print_info(cpu: &CPU, what: Option<&str>) {
+    let bus = &cpu.memory_bus;
+
+    match what {
+        Some("lcd") => println!("{}", bus.gpu.lcd_info()),
+        Some("timer") => println!("{}", bus.clock.info()),
+        Some("int") => println!("IE: {}\nIF: {}\nIME: {} halted: {}",
+                                bus.interrupt_enable, bus.interrupt_flag, cpu.ime(), cpu.halted()),
+        Some("mbc") => println!("{}", bus.memory.mbc_info()),
+        Some("oam") => {
+            for (i, obj) in bus.gpu.obj_set.iter().enumerate() {
+                println!("{:2}: {}", i, obj);
+            }
+        },
+        _ => println!("{}", usage("info")),
+    }
+}
+
+// x/NFS: count, format (x hex, d decimal, c characters, i instructions) and size (b bytes,
+// w little endian words), all optional
+fn examine(cpu: &CPU, spec: &str, address: u16) -> Result<(), String> {
+    let digits = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+    let count = match &spec[..digits] {
+        "" => 1,
+        n => parse::<usize>(n).map_err(|_| format!("bad count {}", n))?,
+    };
+
+    let mut format = 'x';
+    let mut word = false;
+    for c in spec[digits..].chars() {
+        match c {
+            'x' | 'd' | 'c' | 'i' => format = c,
+            'b' => word = false,
+            'w' => word = true,
+            _ => return Err(format!("unknown format {}", c)),
+        }
+    }
+
+    if format == 'i' {
+        let mut address = address;
+        for _ in 0..count {
+            address = address.wrapping_add(print_instruction(cpu, address));
+        }
+        return Ok(());
+    }
+
+    let size: u16 = if word { 2 } else { 1 };
+    let per_line = if word { 8 } else { 16 };
+
+    for line in 0..count.div_ceil(per_line) {
+        let start = address.wrapping_add((line * per_line) as u16 * size);
+        let values: Vec<String> = (0..per_line.min(count - line * per_line))
+            .map(|i| {
+                let a = start.wrapping_add(i as u16 * size);
+                let val = if word {
+                    (cpu.memory_bus.peek(a.wrapping_add(1)) as u16) << 8 | cpu.memory_bus.peek(a) as u16
+                } else {
+                    cpu.memory_bus.peek(a) as u16
+                };
+
+                match (format, word) {
+                    ('d', _) => format!("{:>5}", val),
+                    ('c', _) if (0x20..0x7F).contains(&val) => format!("{}", val as u8 as char),
+                    ('c', _) => String::from("."),
+                    (_, true) => format!("{:04x}", val),
+                    (_, false) => format!("{:02x}", val),
+                }
+            })
+            .collect();
+
+        let separator = if format == 'c' { "" } else { " " };
+        println!("{:#06x}: {}", start, values.join(separator));
+    }
+
+    Ok(())
+}
+
 pub fn print_registers(cpu: &CPU) {
     println!("A: {:#04x}, B: {:#04x}, C: {:#04x}, D: {:#04x}, E: {:#04x}, H: {:#04x}, L: {:#04x}, PC: {:#04x}, SP: {:#04x}",
              cpu.reg.a, cpu.reg.b, cpu.reg.c, cpu.reg.d, cpu.reg.e, cpu.reg.h, cpu.reg.l, cpu.reg.pc, cpu.reg.sp);
@@ -58,24 +230,378 @@ pub fn print_registers(cpu: &CPU) {
 }
 
 
+const LIST_INSTRUCTIONS: usize = 10;
+
+fn print_instruction(cpu: &CPU, address: u16) -> u16 {
+    let instruction = disassemble(&cpu.memory_bus, &cpu.symbols, address);
+
+    let bank = cpu.memory_bus.memory.bank(address);
+    if let Some(name) = cpu.symbols.name(bank, address) {
+        println!("{:02x}:{}:", bank, name);
+    }
+
+    let bytes: Vec<String> = (0..instruction.length)
+        .map(|i| format!("{:02x}", cpu.memory_bus.peek(address.wrapping_add(i))))
+        .collect();
+
+    if address == cpu.reg.pc {
+        print!("->");
+    }
+    println!("\t{:02x}:{:04x}: {:<8}  {}", bank, address, bytes.join(" "), instruction.text);
+
+    instruction.length
+}
+
+fn function_name(cpu: &CPU, address: u16) -> String {
+    let bank = cpu.memory_bus.memory.bank(address);
+    match cpu.symbols.name(bank, address) {
+        Some(name) => format!("{:02x}:{}", bank, name),
+        None => format!("{:#06x}", address),
+    }
+}
+
+// Innermost frame first, each line is where execution is in that frame and the function it's in
+pub fn print_backtrace(cpu: &CPU) {
+    let frames = cpu.call_stack.frames();
+    let mut pc = cpu.reg.pc;
+
+    for (i, frame) in frames.iter().rev().enumerate() {
+        let name = function_name(cpu, frame.target);
+        match frame.kind {
+            FrameKind::Interrupt => println!("#{:<3} {:#06x} in {} (interrupt)", i, pc, name),
+            FrameKind::Call | FrameKind::Rst => println!("#{:<3} {:#06x} in {}", i, pc, name),
+        }
+        pc = frame.call_site;
+    }
+
+    // not called from anything we've seen
+    println!("#{:<3} {:#06x}", frames.len(), pc);
+}
+
+// Disassembles forward from address
 pub fn list_assembly(cpu: &CPU, address: u16) {
-    for a in (address-6)..(address+6) {
-        let b = cpu.memory_bus.read_byte(a);
-        if a == address {
-            print!("->");
+    let mut address = address;
+
+    for _ in 0..LIST_INSTRUCTIONS {
+        address = address.wrapping_add(print_instruction(cpu, address));
+    }
+}
+
+fn is_banked(address: u16) -> bool {
+    matches!(address, 0x4000..=0x7FFF | 0xA000..=0xBFFF)
+}
+
+// A number, BANK:ADDRESS (03:4123) or a symbol name. Numbers are in any bank, symbols in banked
+// areas are in theirs.
+fn parse_location(cpu: &CPU, s: &str) -> Result<(Option<u8>, u16), String> {
+    if s.contains(':') {
+        let (bank, address) = symbols::parse_location(s)?;
+        return Ok((Some(bank), address));
+    }
+
+    match parse::<u16>(s) {
+        Ok(address) => Ok((None, address)),
+        Err(_) => match cpu.symbols.lookup(s) {
+            Some((bank, address)) if is_banked(address) => Ok((Some(bank), address)),
+            Some((_, address)) => Ok((None, address)),
+            None => Err(format!("bad number or unknown symbol {}", s)),
+        },
+    }
+}
+
+// An address to read or write now, so its bank has to be the one mapped
+fn parse_address(cpu: &CPU, s: &str) -> Result<u16, String> {
+    match parse_location(cpu, s)? {
+        (Some(bank), address) if bank != cpu.memory_bus.memory.bank(address) => {
+            Err(format!("bank {:02x} isn't mapped at {:#06x}", bank, address))
+        },
+        (_, address) => Ok(address),
+    }
+}
+
+// ADDR or START-END
+fn parse_range(cpu: &CPU, s: &str) -> Result<(u16, u16), String> {
+    match s.split_once('-') {
+        Some((start, end)) => Ok((parse_address(cpu, start)?, parse_address(cpu, end)?)),
+        None => {
+            let address = parse_address(cpu, s)?;
+            Ok((address, address))
+        },
+    }
+}
+
+// Like parse_range but for watchpoints, which can be in a bank that isn't mapped yet. END takes
+// START's bank.
+fn parse_location_range(cpu: &CPU, s: &str) -> Result<(Option<u8>, u16, u16), String> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let (bank, start) = parse_location(cpu, start)?;
+    match parse_location(cpu, end)? {
+        (Some(end_bank), _) if Some(end_bank) != bank => Err(String::from("a range can't span banks")),
+        (_, end) => Ok((bank, start, end)),
+    }
+}
+
+// watch ADDR[-END] [changed|VALUE], rwatch ADDR[-END], awatch ADDR[-END]
+fn parse_watchpoint(cpu: &CPU, args: &[&str]) -> Result<Watchpoint, String> {
+    let (bank, start, end) = match args.get(1) {
+        Some(range) => parse_location_range(cpu, range)?,
+        None => return Err(usage(args[0])),
+    };
+
+    let kind = match (args[0], args.get(2)) {
+        ("rwatch", None) => WatchKind::Read,
+        ("awatch", None) => WatchKind::Access,
+        ("watch", None) => WatchKind::Write,
+        ("watch", Some(&"changed")) => WatchKind::Change,
+        ("watch", Some(val)) => match parse::<u8>(val) {
+            Ok(val) => WatchKind::Value(val),
+            Err(_) => return Err(String::from("bad number")),
+        },
+        _ => return Err(usage(args[0])),
+    };
+
+    Ok(Watchpoint { start, end, kind, bank })
+}
+
+pub struct Breakpoint {
+    pub address: u16,
+    // only while this bank is mapped there, any if None
+    pub bank: Option<u8>,
+    // as typed and parsed
+    condition: Option<(String, Expr)>,
+    hits: u32,
+    // hits to let through before stopping
+    ignore: u32,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            bank: None,
+            condition: None,
+            hits: 0,
+            ignore: 0,
         }
-        print!("\t{:#04x}: {:#04x}", a, b);
-        let maybe_opcode = Opcode::try_from(b);
-        if maybe_opcode.is_ok() {
-            println!("  # {:?}", maybe_opcode.unwrap());
+    }
+
+    // Whether PC is at it
+    pub fn at(&self, cpu: &CPU) -> bool {
+        cpu.reg.pc == self.address && self.bank.is_none_or(|b| b == cpu.memory_bus.memory.bank(self.address))
+    }
+
+    pub fn condition_holds(&self, cpu: &CPU) -> bool {
+        match &self.condition {
+            Some((_, expr)) => expr.eval(cpu) != 0,
+            None => true,
+        }
+    }
+}
+
+fn location(bank: Option<u8>, address: u16) -> String {
+    match bank {
+        Some(bank) => format!("{:02x}:{:04x}", bank, address),
+        None => format!("{:#06x}", address),
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", location(self.bank, self.address))?;
+        if let Some((condition, _)) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        write!(f, " (hits: {}", self.hits)?;
+        if self.ignore > self.hits {
+            write!(f, ", ignoring {} more", self.ignore - self.hits)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// Commands that set up the breakpoints, watchpoints, catchpoints and displays again, for
+// sessions. Hit counts aren't kept.
+pub fn session_commands(cpu: &CPU) -> Vec<String> {
+    let mut commands = Vec::new();
+
+    for bp in &cpu.breakpoints {
+        match &bp.condition {
+            Some((condition, _)) => commands.push(format!("b {} if {}", location(bp.bank, bp.address), condition)),
+            None => commands.push(format!("b {}", location(bp.bank, bp.address))),
+        }
+    }
+
+    for w in &cpu.memory_bus.watchpoints {
+        let range = if w.start == w.end {
+            location(w.bank, w.start)
         } else {
-            println!("");
+            format!("{}-{}", location(w.bank, w.start), location(w.bank, w.end))
+        };
+        commands.push(match w.kind {
+            WatchKind::Read => format!("rwatch {}", range),
+            WatchKind::Access => format!("awatch {}", range),
+            WatchKind::Write => format!("watch {}", range),
+            WatchKind::Value(val) => format!("watch {} {:#04x}", range, val),
+            WatchKind::Change => format!("watch {} changed", range),
+        });
+    }
+
+    for c in &cpu.catchpoints {
+        commands.push(format!("catch {}", c));
+    }
+
+    for d in &cpu.displays {
+        match d {
+            Display::Register(r) => commands.push(format!("display {}", r)),
+            Display::Memory(address) => commands.push(format!("display {:#06x}", address)),
         }
     }
+
+    commands
 }
 
-pub fn set_breakpoint(cpu: &mut CPU, address: u16) {
-    cpu.breakpoints.push(address);
+// Returns true if a breakpoint at PC should stop execution. Hits are only counted when the
+// condition holds.
+pub fn check_breakpoints(cpu: &mut CPU) -> bool {
+    let hit: Vec<usize> = cpu.breakpoints.iter()
+        .enumerate()
+        .filter(|(_, bp)| bp.at(cpu) && bp.condition_holds(cpu))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut stop = false;
+    for i in hit {
+        let bp = &mut cpu.breakpoints[i];
+        bp.hits += 1;
+        if bp.hits > bp.ignore {
+            stop = true;
+        }
+    }
+
+    stop
+}
+
+pub fn set_breakpoint(cpu: &mut CPU, (bank, address): (Option<u8>, u16), condition: Option<&str>) -> Result<(), String> {
+    let mut bp = Breakpoint::new(address);
+    bp.bank = bank;
+
+    if let Some(condition) = condition {
+        bp.condition = Some((condition.to_string(), expr::parse(condition, &cpu.symbols)?));
+    }
+
+    cpu.breakpoints.push(bp);
+
+    Ok(())
+}
+
+struct Command {
+    // the first one is the name, the rest aliases
+    names: &'static [&'static str],
+    usage: &'static str,
+    help: &'static str,
+    // completed after the name, besides registers and symbols
+    keywords: &'static [&'static str],
+}
+
+const COMMANDS: &[Command] = &[
+    Command { names: &["step", "s"], usage: "step", help: "run one instruction", keywords: &[] },
+    Command { names: &["next", "n"], usage: "next", help: "run one instruction, stepping over calls and rsts", keywords: &[] },
+    Command { names: &["finish"], usage: "finish", help: "run until the current function returns", keywords: &[] },
+    Command { names: &["continue", "c"], usage: "continue", help: "resume the game", keywords: &[] },
+    Command { names: &["frame", "f"], usage: "frame", help: "run until the next frame", keywords: &[] },
+    Command { names: &["reverse-step", "rs"], usage: "reverse-step", help: "go back one instruction", keywords: &[] },
+    Command { names: &["reverse-continue", "rc"], usage: "reverse-continue", help: "go back to the last breakpoint or watchpoint hit", keywords: &[] },
+    Command { names: &["backtrace", "bt"], usage: "backtrace", help: "show the call stack", keywords: &[] },
+    Command { names: &["list", "l"], usage: "list [ADDRESS]", help: "disassemble from PC or ADDRESS", keywords: &[] },
+    Command { names: &["print", "p"], usage: "print [ADDRESS]", help: "print the registers or a byte", keywords: &[] },
+    Command { names: &["x"], usage: "x/NFS ADDRESS", help: "dump N values in format F (x, d, c, i) and size S (b, w)", keywords: &[] },
+    Command { names: &["info", "i"], usage: "info lcd|timer|int|mbc|oam", help: "show the state of a part of the Game Boy", keywords: &["lcd", "timer", "int", "mbc", "oam"] },
+    Command { names: &["set"], usage: "set reg REGISTER VALUE | set mem ADDRESS BYTE...", help: "change a register or memory", keywords: &["reg", "mem"] },
+    Command { names: &["fill"], usage: "fill START-END BYTE", help: "fill a range of memory", keywords: &[] },
+    Command { names: &["copy"], usage: "copy START-END DESTINATION", help: "copy a range of memory", keywords: &[] },
+    Command { names: &["display", "d"], usage: "display [REGISTER|ADDRESS]", help: "list displays or add one, shown every time gbdb stops", keywords: &[] },
+    Command { names: &["undisplay"], usage: "undisplay [N]", help: "remove one or all displays", keywords: &[] },
+    Command { names: &["breakpoint", "b", "bp"], usage: "breakpoint [LOCATION [if CONDITION]]", help: "list breakpoints or set one", keywords: &["if"] },
+    Command { names: &["condition"], usage: "condition BREAKPOINT [CONDITION]", help: "change or remove a breakpoint's condition", keywords: &[] },
+    Command { names: &["ignore"], usage: "ignore BREAKPOINT COUNT", help: "let a breakpoint's next COUNT hits through", keywords: &[] },
+    Command { names: &["delete"], usage: "delete [BREAKPOINT]", help: "delete one or all breakpoints", keywords: &[] },
+    Command { names: &["watch"], usage: "watch [ADDRESS[-END] [changed|VALUE]]", help: "list watchpoints or stop on writes", keywords: &["changed"] },
+    Command { names: &["rwatch"], usage: "rwatch [ADDRESS[-END]]", help: "list watchpoints or stop on reads", keywords: &[] },
+    Command { names: &["awatch"], usage: "awatch [ADDRESS[-END]]", help: "list watchpoints or stop on reads and writes", keywords: &[] },
+    Command { names: &["unwatch"], usage: "unwatch [N]", help: "delete one or all watchpoints", keywords: &[] },
+    Command { names: &["catch"], usage: "catch [EVENT]", help: "list catchpoints or stop on an event", keywords: &["interrupt", "vblank", "ly", "bank", "dma", "halt", "stop", "illegal"] },
+    Command { names: &["uncatch"], usage: "uncatch [N]", help: "delete one or all catchpoints", keywords: &[] },
+    Command { names: &["save"], usage: "save [SLOT]", help: "save the state to a slot", keywords: &[] },
+    Command { names: &["load"], usage: "load [SLOT]", help: "load the state from a slot", keywords: &[] },
+    Command { names: &["script"], usage: "script FILE", help: "run a Rhai script", keywords: &[] },
+    Command { names: &["source"], usage: "source FILE", help: "run the gbdb commands in a file", keywords: &[] },
+    Command { names: &["help", "h"], usage: "help [COMMAND]", help: "list commands or explain one", keywords: &[] },
+];
+
+const REGISTER_NAMES: [&str; 10] = ["a", "b", "c", "d", "e", "f", "h", "l", "sp", "pc"];
+
+// By name, alias or unique prefix
+fn find_command(name: &str) -> Result<&'static Command, String> {
+    if let Some(command) = COMMANDS.iter().find(|c| c.names.contains(&name)) {
+        return Ok(command);
+    }
+
+    let matches: Vec<&Command> = COMMANDS.iter()
+        .filter(|c| c.names.iter().any(|n| n.starts_with(name)))
+        .collect();
+    match matches[..] {
+        [command] => Ok(command),
+        [] => Err(format!("{}: Command not found, try help", name)),
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|c| c.names[0]).collect();
+            Err(format!("{}: ambiguous, could be {}", name, names.join(", ")))
+        },
+    }
+}
+
+fn usage(name: &str) -> String {
+    match find_command(name) {
+        Ok(command) => format!("Usage: {}", command.usage),
+        Err(e) => e,
+    }
+}
+
+// Completes command names, then registers, symbols and the command's keywords
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct GbdbHelper {
+    symbols: Vec<String>,
+}
+
+impl Completer for GbdbHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..];
+
+        let mut candidates: Vec<&str> = if start == 0 {
+            COMMANDS.iter().map(|c| c.names[0]).collect()
+        } else {
+            let keywords = line.split_whitespace()
+                .next()
+                .and_then(|name| find_command(name).ok())
+                .map_or(&[][..], |c| c.keywords);
+            keywords.iter()
+                .chain(REGISTER_NAMES.iter())
+                .copied()
+                .chain(self.symbols.iter().map(|s| s.as_str()))
+                .collect()
+        };
+        candidates.retain(|c| c.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+
+        let pairs = candidates.into_iter()
+            .map(|c| Pair { display: c.to_string(), replacement: c.to_string() })
+            .collect();
+        Ok((start, pairs))
+    }
 }
 
 pub fn drop_to_shell(cpu: &mut CPU) -> rustyline::Result<DebuggerRet> {
@@ -84,101 +610,36 @@ pub fn drop_to_shell(cpu: &mut CPU) -> rustyline::Result<DebuggerRet> {
         .completion_type(CompletionType::List)
         .edit_mode(EditMode::Emacs)
         .build();
-    let mut rl = Editor::<()>::with_config(config)?;
-    if rl.load_history(".gbdb_history").is_err() {
+    let mut rl = Editor::<GbdbHelper>::with_config(config)?;
+    rl.set_helper(Some(GbdbHelper {
+        symbols: cpu.symbols.names().map(String::from).collect(),
+    }));
+    let history_path = session::history_path();
+    if rl.load_history(&history_path).is_err() {
         println!("no history");
     }
 
-    let mut ret = DebuggerRet::Continue;
+    print_instruction(cpu, cpu.reg.pc);
+    print_displays(cpu);
 
-    let b = cpu.memory_bus.read_byte(cpu.reg.pc.into());
-    print!("{:#04x}:\t{:#04x}", cpu.reg.pc, b);
-    let maybe_opcode = Opcode::try_from(b);
-    if maybe_opcode.is_ok() {
-        println!("  # {:?}", maybe_opcode.unwrap());
-    } else {
-        println!("");
-    }
-
-    loop {
+    let ret = loop {
         let readline = rl.readline("(gbdb) ");
 
         match readline {
             Ok(line) => {
-                let l: &str;
-                if line == "" {
-                    l = rl.history().last().unwrap();
+                // an empty line repeats the last command
+                let line = if line.is_empty() {
+                    match rl.history().last() {
+                        Some(last) => last.clone(),
+                        None => continue,
+                    }
                 } else {
-                    l = line.as_str();
                     rl.add_history_entry(line.as_str());
-                }
+                    line
+                };
 
-                let l_split: Vec<&str> = l.split(" ").collect();
-                if l_split.len() < 1 {
-                    continue;
-                }
-
-                match l_split[0] {
-                    "p"|"print" => {
-                        if l_split.len() < 2 {
-                            print_registers(cpu);
-                            continue;
-                        }
-
-                        let address = parse::<u16>(l_split[1]);
-                        match address {
-                            Ok(a) => println!("{:#04x}", cpu.memory_bus.read_byte(a)),
-                            Err(_) => { println!("bad number"); continue; },
-                        };
-                    }
-                    "s"|"step"|"next"|"n" => { ret = DebuggerRet::Step; break },
-                    "l"|"list" => {
-                        if l_split.len() < 2 {
-                            list_assembly(cpu, cpu.reg.pc);
-                            continue;
-                        }
-
-                        let address = parse::<u16>(l_split[1]);
-                        match address {
-                            Ok(a) => list_assembly(cpu, a),
-                            Err(_) => { println!("bad number"); continue; },
-                        };
-                    }
-                    "c"|"continue" => { ret = DebuggerRet::Continue; break },
-                    "b"|"bp"|"breakpoint" => {
-                        if l_split.len() < 2 {
-                            for i in 0..cpu.breakpoints.len() {
-                                println!("{}: {:#04x}", i, cpu.breakpoints[i]);
-                            }
-                            continue;
-                        }
-
-                        let address = parse::<u16>(l_split[1]);
-                        match address {
-                            Ok(a) => set_breakpoint(cpu, a),
-                            Err(_) => { println!("bad number"); continue; },
-                        };
-                    },
-                    "d"|"display" => {
-                        if l_split.len() < 2 {
-                            println!("Usage: {} REGISTER", l_split[0]);
-                            continue;
-                        }
-                    },
-                    "delete" => {
-                        if l_split.len() < 2 {
-                            cpu.breakpoints.clear();
-                            continue;
-                        }
-
-                        let bp_index = parse::<usize>(l_split[1]);
-                        match bp_index {
-                            Ok(idx) => cpu.breakpoints.remove(idx),
-                            Err(_) => { println!("bad number"); continue; },
-                        };
-                    }
-                    "f"|"frame" => { ret = DebuggerRet::Frame; break; },
-                    &_ => println!("{}: Command not found", line.as_str()),
+                if let Some(ret) = run_command(cpu, &line) {
+                    break ret;
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -187,15 +648,453 @@ pub fn drop_to_shell(cpu: &mut CPU) -> rustyline::Result<DebuggerRet> {
             }
             Err(ReadlineError::Eof) => {
                 println!("Exiting...");
+                session::save(cpu);
                 process::exit(1);
             }
             Err(err) => {
                 println!("Error: {err:?}");
-                break;
+                break DebuggerRet::Continue;
             }
         }
+    };
+    rl.append_history(&history_path)?;
+    session::save(cpu);
+
+    Ok(ret)
+}
+
+// Runs a gbdb command, returns how to resume if it's one that resumes
+fn run_command(cpu: &mut CPU, line: &str) -> Option<DebuggerRet> {
+    let mut l_split: Vec<&str> = line.split_whitespace().collect();
+    if l_split.is_empty() {
+        return None;
     }
-    rl.append_history(".gbdb_history")?;
 
-    return Ok(ret);
+    // x/NFS keeps its format after the name
+    let (name, format) = match l_split[0].split_once('/') {
+        Some((name, format)) => (name, Some(format)),
+        None => (l_split[0], None),
+    };
+    let command = match find_command(name) {
+        Ok(command) => command,
+        Err(e) => { println!("{}", e); return None; },
+    };
+    let name = match format {
+        Some(format) => format!("{}/{}", command.names[0], format),
+        None => command.names[0].to_string(),
+    };
+    l_split[0] = &name;
+
+    match l_split[0] {
+        "print" => {
+            if l_split.len() < 2 {
+                print_registers(cpu);
+                return None;
+            }
+
+            let address = parse_address(cpu, l_split[1]);
+            match address {
+                Ok(a) => println!("{:#04x}", cpu.memory_bus.peek(a)),
+                Err(e) => { println!("{}", e); return None; },
+            };
+        }
+        "step" => return Some(DebuggerRet::Step),
+        "next" => return Some(DebuggerRet::Next),
+        "finish" => {
+            if cpu.call_stack.frames().is_empty() {
+                println!("not in a function");
+                return None;
+            }
+            return Some(DebuggerRet::Finish);
+        },
+        "reverse-step" => match history::reverse_step(cpu) {
+            Ok(()) => {
+                print_instruction(cpu, cpu.reg.pc);
+                print_displays(cpu);
+            },
+            Err(e) => println!("can't go back: {}", e),
+        },
+        "reverse-continue" => match history::reverse_continue(cpu) {
+            Ok(hit) => {
+                if !hit {
+                    println!("reached the start of the history");
+                }
+                print_instruction(cpu, cpu.reg.pc);
+                print_displays(cpu);
+            },
+            Err(e) => println!("can't go back: {}", e),
+        },
+        "backtrace" => print_backtrace(cpu),
+        "list" => {
+            if l_split.len() < 2 {
+                list_assembly(cpu, cpu.reg.pc);
+                return None;
+            }
+
+            let address = parse_address(cpu, l_split[1]);
+            match address {
+                Ok(a) => list_assembly(cpu, a),
+                Err(e) => { println!("{}", e); return None; },
+            };
+        }
+        "continue" => return Some(DebuggerRet::Continue),
+        "breakpoint" => {
+            if l_split.len() < 2 {
+                for (i, bp) in cpu.breakpoints.iter().enumerate() {
+                    println!("{}: {}", i, bp);
+                }
+                return None;
+            }
+
+            // b ADDRESS [if CONDITION]
+            let condition = match l_split.get(2) {
+                Some(&"if") => Some(l_split[3..].join(" ")),
+                Some(_) => { println!("{}", usage("breakpoint")); return None; },
+                None => None,
+            };
+
+            let location = parse_location(cpu, l_split[1]);
+            let r = match location {
+                Ok(location) => set_breakpoint(cpu, location, condition.as_deref()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = r {
+                println!("{}", e);
+            }
+        },
+        "condition" => {
+            // condition N [CONDITION], without one the breakpoint is unconditional
+            let bp_index = match l_split.get(1).map(|n| parse::<usize>(n)) {
+                Some(Ok(idx)) if idx < cpu.breakpoints.len() => idx,
+                _ => { println!("{}", usage("condition")); return None; },
+            };
+
+            if l_split.len() < 3 {
+                cpu.breakpoints[bp_index].condition = None;
+                return None;
+            }
+
+            let condition = l_split[2..].join(" ");
+            match expr::parse(&condition, &cpu.symbols) {
+                Ok(e) => cpu.breakpoints[bp_index].condition = Some((condition, e)),
+                Err(e) => println!("{}", e),
+            };
+        },
+        "ignore" => {
+            // ignore N COUNT: don't stop for the next COUNT hits
+            let args = (l_split.get(1).map(|n| parse::<usize>(n)), l_split.get(2).map(|n| parse::<u32>(n)));
+            match args {
+                (Some(Ok(idx)), Some(Ok(count))) if idx < cpu.breakpoints.len() => {
+                    let bp = &mut cpu.breakpoints[idx];
+                    bp.ignore = bp.hits + count;
+                },
+                _ => println!("{}", usage("ignore")),
+            };
+        },
+        "display" => {
+            if l_split.len() < 2 {
+                print_displays(cpu);
+                return None;
+            }
+
+            // a register or an address
+            let display = match Registers::try_from(l_split[1]) {
+                Ok(r) => Ok(Display::Register(r)),
+                Err(_) => parse_address(cpu, l_split[1]).map(Display::Memory),
+            };
+            match display {
+                Ok(d) => {
+                    println!("{}: {}", cpu.displays.len(), d.show(cpu));
+                    cpu.displays.push(d);
+                },
+                Err(e) => println!("{}", e),
+            };
+        },
+        "undisplay" => {
+            if l_split.len() < 2 {
+                cpu.displays.clear();
+                return None;
+            }
+
+            match parse::<usize>(l_split[1]) {
+                Ok(idx) if idx < cpu.displays.len() => { cpu.displays.remove(idx); },
+                _ => println!("bad display number"),
+            };
+        },
+        "info" => print_info(cpu, l_split.get(1).copied()),
+        "set" => {
+            let r = match l_split.get(1) {
+                // set reg REGISTER VALUE
+                Some(&"reg") if l_split.len() == 4 => {
+                    match (Registers::try_from(l_split[2]), parse::<u16>(l_split[3])) {
+                        (Ok(r), Ok(val)) => set_register(cpu, r, val),
+                        (Err(e), _) => Err(String::from(e)),
+                        (_, Err(_)) => Err(String::from("bad number")),
+                    }
+                },
+                // set mem ADDRESS BYTE...
+                Some(&"mem") if l_split.len() >= 4 => {
+                    let bytes: Result<Vec<u8>, _> = l_split[3..].iter().map(|b| parse::<u8>(b)).collect();
+                    match (parse_address(cpu, l_split[2]), bytes) {
+                        (Ok(address), Ok(bytes)) => {
+                            for (i, b) in bytes.iter().enumerate() {
+                                cpu.memory_bus.poke(address.wrapping_add(i as u16), *b);
+                            }
+                            Ok(())
+                        },
+                        (Err(e), _) => Err(e),
+                        (_, Err(_)) => Err(String::from("bad byte")),
+                    }
+                },
+                _ => Err(usage("set")),
+            };
+
+            if let Err(e) = r {
+                println!("{}", e);
+            }
+        },
+        "fill" => {
+            // fill START-END BYTE
+            let args = (l_split.get(1).map(|r| parse_range(cpu, r)), l_split.get(2).map(|b| parse::<u8>(b)));
+            match args {
+                (Some(Ok((start, end))), Some(Ok(val))) => {
+                    for address in start..=end {
+                        cpu.memory_bus.poke(address, val);
+                    }
+                },
+                (Some(Err(e)), _) => println!("{}", e),
+                _ => println!("{}", usage("fill")),
+            };
+        },
+        "copy" => {
+            // copy START-END DESTINATION
+            let args = (l_split.get(1).map(|r| parse_range(cpu, r)), l_split.get(2).map(|d| parse_address(cpu, d)));
+            match args {
+                (Some(Ok((start, end))), Some(Ok(dest))) => {
+                    // read everything first so overlapping copies work
+                    let bytes: Vec<u8> = (start..=end).map(|a| cpu.memory_bus.peek(a)).collect();
+                    for (i, b) in bytes.iter().enumerate() {
+                        cpu.memory_bus.poke(dest.wrapping_add(i as u16), *b);
+                    }
+                },
+                (Some(Err(e)), _) | (_, Some(Err(e))) => println!("{}", e),
+                _ => println!("{}", usage("copy")),
+            };
+        },
+        x if x == "x" || x.starts_with("x/") => {
+            let r = match l_split.get(1) {
+                Some(address) => parse_address(cpu, address)
+                    .and_then(|a| examine(cpu, x.strip_prefix("x/").unwrap_or(""), a)),
+                None => Err(usage("x")),
+            };
+
+            if let Err(e) = r {
+                println!("{}", e);
+            }
+        },
+        "delete" => {
+            if l_split.len() < 2 {
+                cpu.breakpoints.clear();
+                return None;
+            }
+
+            match parse::<usize>(l_split[1]) {
+                Ok(idx) if idx < cpu.breakpoints.len() => {
+                    cpu.breakpoints.remove(idx);
+                },
+                _ => println!("bad breakpoint number"),
+            };
+        },
+        "frame" => return Some(DebuggerRet::Frame),
+        "watch"|"rwatch"|"awatch" => {
+            if l_split.len() < 2 {
+                for (i, w) in cpu.memory_bus.watchpoints.iter().enumerate() {
+                    println!("{}: {}", i, w);
+                }
+                return None;
+            }
+
+            match parse_watchpoint(cpu, &l_split) {
+                Ok(w) => cpu.memory_bus.watchpoints.push(w),
+                Err(e) => println!("{}", e),
+            };
+        },
+        "catch" => {
+            if l_split.len() < 2 {
+                for (i, c) in cpu.catchpoints.iter().enumerate() {
+                    println!("{}: {}", i, c);
+                }
+                return None;
+            }
+
+            match Catchpoint::try_from(&l_split[1..]) {
+                Ok(c) => cpu.catchpoints.push(c),
+                Err(e) => println!("{}", e),
+            };
+        },
+        "uncatch" => {
+            if l_split.len() < 2 {
+                cpu.catchpoints.clear();
+                return None;
+            }
+
+            match parse::<usize>(l_split[1]) {
+                Ok(idx) if idx < cpu.catchpoints.len() => {
+                    cpu.catchpoints.remove(idx);
+                },
+                _ => println!("bad catchpoint number"),
+            };
+        },
+        "unwatch" => {
+            if l_split.len() < 2 {
+                cpu.memory_bus.watchpoints.clear();
+                return None;
+            }
+
+            match parse::<usize>(l_split[1]) {
+                Ok(idx) if idx < cpu.memory_bus.watchpoints.len() => {
+                    cpu.memory_bus.watchpoints.remove(idx);
+                },
+                _ => println!("bad watchpoint number"),
+            };
+        },
+        "load"|"script" if cpu.movie_active => {
+            println!("can't {} while recording or replaying a movie", l_split[0]);
+        },
+        "save"|"load" => {
+            let slot = if l_split.len() < 2 {
+                Ok(0)
+            } else {
+                parse::<u8>(l_split[1])
+            };
+
+            let slot = match slot {
+                Ok(s) => s,
+                Err(_) => { println!("bad number"); return None; },
+            };
+
+            let r = if l_split[0] == "save" {
+                save_state::save_slot(cpu, slot)
+            } else {
+                save_state::load_slot(cpu, slot)
+            };
+            match r {
+                Ok(path) => println!("{}: {}", l_split[0], path.display()),
+                Err(e) => println!("can't {} state: {}", l_split[0], e),
+            };
+        },
+        "script" => {
+            if l_split.len() < 2 {
+                println!("{}", usage("script"));
+                return None;
+            }
+
+            if let Err(e) = script::run(cpu, l_split[1]) {
+                println!("script failed: {}", e);
+            }
+        },
+        "source" => match l_split.get(1) {
+            Some(path) => match source(cpu, path) {
+                Ok(ret) => return ret,
+                Err(e) => println!("{}", e),
+            },
+            None => println!("{}", usage("source")),
+        },
+        "help" => match l_split.get(1) {
+            Some(name) => match find_command(name) {
+                Ok(command) => {
+                    println!("Usage: {}\n{}", command.usage, command.help);
+                    if command.names.len() > 1 {
+                        println!("Aliases: {}", command.names[1..].join(", "));
+                    }
+                },
+                Err(e) => println!("{}", e),
+            },
+            None => {
+                for command in COMMANDS {
+                    println!("{:<40} {}", command.usage, command.help);
+                }
+            },
+        },
+        &_ => unreachable!("{} is in COMMANDS but not handled", l_split[0]),
+    }
+
+    None
+}
+
+// Files can source each other, but not forever
+const MAX_SOURCE_DEPTH: usize = 16;
+
+// Runs the commands in a file, one per line, skipping blank lines and # comments. Stops at a
+// command that resumes and returns it.
+pub fn source(cpu: &mut CPU, path: &str) -> Result<Option<DebuggerRet>, String> {
+    if cpu.sourcing >= MAX_SOURCE_DEPTH {
+        return Err(format!("{}: sourcing more than {} files deep", path, MAX_SOURCE_DEPTH));
+    }
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    cpu.sourcing += 1;
+    let ret = contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| run_command(cpu, line));
+    cpu.sourcing -= 1;
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_commands_by_alias_and_prefix() {
+        assert_eq!(find_command("bp").unwrap().names[0], "breakpoint");
+        // an alias wins over being a prefix
+        assert_eq!(find_command("d").unwrap().names[0], "display");
+        assert_eq!(find_command("de").unwrap().names[0], "delete");
+        assert_eq!(find_command("reverse-c").unwrap().names[0], "reverse-continue");
+
+        assert!(matches!(find_command("re"), Err(e) if e.contains("ambiguous")));
+        assert!(find_command("nope").is_err());
+    }
+
+    #[test]
+    fn deletes_only_existing_breakpoints() {
+        let mut cpu = CPU::from_test_rom(&[]);
+
+        run_command(&mut cpu, "b 0x150");
+        run_command(&mut cpu, "delete 1");
+        assert_eq!(cpu.breakpoints.len(), 1);
+        run_command(&mut cpu, "delete 0");
+        assert!(cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn keeps_movies_in_sync() {
+        let mut cpu = CPU::from_test_rom(&[]);
+
+        run_command(&mut cpu, "save");
+        cpu.reg.a = 0x42;
+        cpu.movie_active = true;
+        run_command(&mut cpu, "load");
+        assert_eq!(cpu.reg.a, 0x42);
+
+        cpu.movie_active = false;
+        run_command(&mut cpu, "load");
+        assert_ne!(cpu.reg.a, 0x42);
+        fs::remove_file(save_state::slot_path(&cpu.rom_path, 0)).unwrap();
+    }
+
+    #[test]
+    fn stops_sourcing_itself() {
+        let mut cpu = CPU::from_test_rom(&[]);
+
+        let path = std::path::Path::new(&cpu.rom_path).with_extension("gbdb");
+        fs::write(&path, format!("b 0x150\nsource {}\n", path.display())).unwrap();
+        assert!(matches!(source(&mut cpu, &path.to_string_lossy()), Ok(None)));
+        assert_eq!(cpu.breakpoints.len(), MAX_SOURCE_DEPTH);
+        assert_eq!(cpu.sourcing, 0);
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,183 @@
+use crate::memory_bus::MemoryBus;
+use crate::symbols::Symbols;
+
+// SM83 disassembler emitting RGBDS syntax, see https://gbdev.io/gb-opcodes/optables/
+//
+// Opcodes are decoded from their bit fields: xx yyy zzz, where yyy is split into pp q for the
+// instructions working on 16-bit registers.
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+// push and pop use af instead of sp
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+// ld [r16], a and ld a, [r16]
+const R16_MEM: [&str; 4] = ["[bc]", "[de]", "[hli]", "[hld]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp"];
+const ACCUMULATOR: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const ROTATIONS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+pub struct Instruction {
+    pub length: u16,
+    pub text: String,
+    // address operand, if any
+    pub target: Option<u16>,
+}
+
+// Decodes the instruction at address. bytes are the bytes at address onwards, instructions are at
+// most 3 bytes long.
+pub fn decode(address: u16, bytes: [u8; 3]) -> Instruction {
+    let op = bytes[0];
+    let n8 = bytes[1];
+    let n16 = (bytes[2] as u16) << 8 | bytes[1] as u16;
+    let e8 = bytes[1] as i8;
+
+    let x = op >> 6;
+    let y = ((op >> 3) & 0x7) as usize;
+    let z = op & 0x7;
+    let p = y >> 1;
+    let q = y & 0x1;
+
+    // relative jumps are relative to the next instruction
+    let jr_target = address.wrapping_add(2).wrapping_add(e8 as u16);
+
+    let ldh_address = 0xFF00 | n8 as u16;
+
+    let target = match (x, z) {
+        (0, 0) if y == 1 => Some(n16),
+        (0, 0) if y >= 3 => Some(jr_target),
+        (0, 1) if q == 0 => Some(n16),
+        (3, 0) if y == 4 || y == 6 => Some(ldh_address),
+        (3, 2) if y < 4 || y == 5 || y == 7 => Some(n16),
+        (3, 3) if y == 0 => Some(n16),
+        (3, 4) if y < 4 => Some(n16),
+        (3, 5) if q == 1 && p == 0 => Some(n16),
+        _ => None,
+    };
+
+    let (length, text) = match (x, z) {
+        (0, 0) => match y {
+            0 => (1, String::from("nop")),
+            1 => (3, format!("ld [${:04X}], sp", n16)),
+            2 => (2, String::from("stop")),
+            3 => (2, format!("jr ${:04X}", jr_target)),
+            _ => (2, format!("jr {}, ${:04X}", CONDITIONS[y - 4], jr_target)),
+        },
+        (0, 1) if q == 0 => (3, format!("ld {}, ${:04X}", R16[p], n16)),
+        (0, 1) => (1, format!("add hl, {}", R16[p])),
+        (0, 2) if q == 0 => (1, format!("ld {}, a", R16_MEM[p])),
+        (0, 2) => (1, format!("ld a, {}", R16_MEM[p])),
+        (0, 3) if q == 0 => (1, format!("inc {}", R16[p])),
+        (0, 3) => (1, format!("dec {}", R16[p])),
+        (0, 4) => (1, format!("inc {}", R8[y])),
+        (0, 5) => (1, format!("dec {}", R8[y])),
+        (0, 6) => (2, format!("ld {}, ${:02X}", R8[y], n8)),
+        (0, 7) => (1, String::from(ACCUMULATOR[y])),
+
+        (1, 6) if y == 6 => (1, String::from("halt")),
+        (1, _) => (1, format!("ld {}, {}", R8[y], R8[z as usize])),
+
+        (2, _) => (1, format!("{} {}", ALU[y], R8[z as usize])),
+
+        (3, 0) => match y {
+            0..=3 => (1, format!("ret {}", CONDITIONS[y])),
+            4 => (2, format!("ldh [${:04X}], a", ldh_address)),
+            5 => (2, format!("add sp, {}", e8)),
+            6 => (2, format!("ldh a, [${:04X}]", ldh_address)),
+            _ => (2, format!("ld hl, sp{:+}", e8)),
+        },
+        (3, 1) if q == 0 => (1, format!("pop {}", R16_STACK[p])),
+        (3, 1) => match p {
+            0 => (1, String::from("ret")),
+            1 => (1, String::from("reti")),
+            2 => (1, String::from("jp hl")),
+            _ => (1, String::from("ld sp, hl")),
+        },
+        (3, 2) => match y {
+            0..=3 => (3, format!("jp {}, ${:04X}", CONDITIONS[y], n16)),
+            4 => (1, String::from("ldh [c], a")),
+            5 => (3, format!("ld [${:04X}], a", n16)),
+            6 => (1, String::from("ldh a, [c]")),
+            _ => (3, format!("ld a, [${:04X}]", n16)),
+        },
+        (3, 3) => match y {
+            0 => (3, format!("jp ${:04X}", n16)),
+            1 => (2, decode_prefixed(n8)),
+            6 => (1, String::from("di")),
+            7 => (1, String::from("ei")),
+            _ => (1, format!("db ${:02X}", op)),
+        },
+        (3, 4) if y < 4 => (3, format!("call {}, ${:04X}", CONDITIONS[y], n16)),
+        (3, 5) if q == 0 => (1, format!("push {}", R16_STACK[p])),
+        (3, 5) if p == 0 => (3, format!("call ${:04X}", n16)),
+        (3, 6) => (2, format!("{} ${:02X}", ALU[y], n8)),
+        (3, 7) => (1, format!("rst ${:02X}", y * 8)),
+
+        // the rest of 0xC0-0xFF are invalid opcodes
+        _ => (1, format!("db ${:02X}", op)),
+    };
+
+    Instruction {
+        length,
+        text,
+        target,
+    }
+}
+
+fn decode_prefixed(op: u8) -> String {
+    let y = ((op >> 3) & 0x7) as usize;
+    let r = R8[(op & 0x7) as usize];
+
+    match op >> 6 {
+        0 => format!("{} {}", ROTATIONS[y], r),
+        1 => format!("bit {}, {}", y, r),
+        2 => format!("res {}, {}", y, r),
+        _ => format!("set {}, {}", y, r),
+    }
+}
+
+// Decodes the instruction at address, naming address operands that have a symbol
+pub fn disassemble(memory_bus: &MemoryBus, symbols: &Symbols, address: u16) -> Instruction {
+    let bytes = [
+        memory_bus.peek(address),
+        memory_bus.peek(address.wrapping_add(1)),
+        memory_bus.peek(address.wrapping_add(2)),
+    ];
+
+    let mut instruction = decode(address, bytes);
+
+    if let Some(target) = instruction.target {
+        if let Some(name) = symbols.name(memory_bus.memory.bank(target), target) {
+            instruction.text = instruction.text.replace(&format!("${:04X}", target), name);
+        }
+    }
+
+    instruction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(address: u16, bytes: [u8; 3]) -> (u16, String) {
+        let instruction = decode(address, bytes);
+        (instruction.length, instruction.text)
+    }
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(text(0x100, [0x00, 0xC3, 0x50]), (1, String::from("nop")));
+        assert_eq!(text(0x101, [0xC3, 0x50, 0x01]), (3, String::from("jp $0150")));
+        assert_eq!(text(0x150, [0x3E, 0x42, 0x00]), (2, String::from("ld a, $42")));
+        assert_eq!(text(0x150, [0xE0, 0x40, 0x00]), (2, String::from("ldh [$FF40], a")));
+        assert_eq!(text(0x150, [0x20, 0xFE, 0x00]), (2, String::from("jr nz, $0150")));
+        assert_eq!(text(0x150, [0xF8, 0xFE, 0x00]), (2, String::from("ld hl, sp-2")));
+        assert_eq!(text(0x150, [0x7E, 0x00, 0x00]), (1, String::from("ld a, [hl]")));
+        assert_eq!(text(0x150, [0x22, 0x00, 0x00]), (1, String::from("ld [hli], a")));
+        assert_eq!(text(0x150, [0xA8, 0x00, 0x00]), (1, String::from("xor b")));
+        assert_eq!(text(0x150, [0xCB, 0x7C, 0x00]), (2, String::from("bit 7, h")));
+        assert_eq!(text(0x150, [0xCB, 0x37, 0x00]), (2, String::from("swap a")));
+        assert_eq!(text(0x150, [0xFF, 0x00, 0x00]), (1, String::from("rst $38")));
+        assert_eq!(text(0x150, [0xD3, 0x00, 0x00]), (1, String::from("db $D3")));
+    }
+}
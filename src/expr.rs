@@ -0,0 +1,332 @@
+use parse_int::parse as parse_int;
+
+use crate::cpu::CPU;
+use crate::registers::Flag;
+use crate::symbols::Symbols;
+
+// Expressions for gbdb, e.g. breakpoint conditions:
+//
+//   a == $12 && bank == 3
+//   [hl] != 0 || zf
+//   [wPlayerX] >= $80
+//
+// Values are registers (a, b, c, d, e, f, h, l, af, bc, de, hl, sp, pc), flags (zf, nf, hf, cf),
+// the ROM bank mapped at 0x4000 (bank), the current scanline (ly), numbers ($ff, %1010, 0xff,
+// 255), symbol names and memory reads ([hl], [$c000]). Comparisons and boolean operators give 0
+// or 1, anything non-zero is true.
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Register {
+    A, B, C, D, E, F, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Flag(Flag),
+    Bank,
+    Ly,
+    Deref(Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// longest first so "<=" isn't read as "<"
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=",
+    "<", ">", "|", "^", "&", "+", "-", "!", "(", ")", "[", "]",
+];
+
+fn parse_number(s: &str) -> Result<i64, String> {
+    // RGBDS style prefixes
+    let n = if let Some(hex) = s.strip_prefix('$') {
+        format!("0x{}", hex)
+    } else if let Some(bin) = s.strip_prefix('%') {
+        format!("0b{}", bin)
+    } else {
+        s.to_string()
+    };
+
+    parse_int::<i64>(&n).map_err(|_| format!("bad number {}", s))
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+
+        let len = if c.is_ascii_digit() || c == '$' || c == '%' {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '$' && c != '%' && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..len])?));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    op.len()
+                },
+                None => return Err(format!("unexpected {}", c)),
+            }
+        };
+
+        rest = rest[len..].trim_start();
+    }
+
+    Ok(tokens)
+}
+
+// Precedence climbing, loosest binding first
+const LEVELS: [&[(&str, BinaryOp)]; 7] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne), ("<=", BinaryOp::Le), (">=", BinaryOp::Ge),
+      ("<", BinaryOp::Lt), (">", BinaryOp::Gt)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Op(o)) if o == op => Ok(()),
+            _ => Err(format!("expected {}", op)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(o)) => LEVELS[level].iter().find(|(s, _)| s == o).map(|(_, op)| *op),
+                _ => None,
+            };
+
+            match op {
+                Some(op) => {
+                    self.pos += 1;
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                },
+                None => return Ok(lhs),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op("!")) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Op("(")) => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            Some(Token::Op("[")) => {
+                let expr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Deref(Box::new(expr)))
+            },
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => self.ident(&name),
+            Some(Token::Op(op)) => Err(format!("unexpected {}", op)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+
+    fn ident(&self, name: &str) -> Result<Expr, String> {
+        let expr = match name.to_lowercase().as_str() {
+            "a" => Expr::Register(Register::A),
+            "b" => Expr::Register(Register::B),
+            "c" => Expr::Register(Register::C),
+            "d" => Expr::Register(Register::D),
+            "e" => Expr::Register(Register::E),
+            "f" => Expr::Register(Register::F),
+            "h" => Expr::Register(Register::H),
+            "l" => Expr::Register(Register::L),
+            "af" => Expr::Register(Register::AF),
+            "bc" => Expr::Register(Register::BC),
+            "de" => Expr::Register(Register::DE),
+            "hl" => Expr::Register(Register::HL),
+            "sp" => Expr::Register(Register::SP),
+            "pc" => Expr::Register(Register::PC),
+            "zf" => Expr::Flag(Flag::Z),
+            "nf" => Expr::Flag(Flag::N),
+            "hf" => Expr::Flag(Flag::H),
+            "cf" => Expr::Flag(Flag::C),
+            "bank" => Expr::Bank,
+            "ly" => Expr::Ly,
+            _ => match self.symbols.lookup(name) {
+                Some((_, address)) => Expr::Number(address as i64),
+                None => return Err(format!("unknown name {}", name)),
+            },
+        };
+
+        Ok(expr)
+    }
+}
+
+pub fn parse(input: &str, symbols: &Symbols) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        symbols,
+    };
+
+    let expr = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        let reg = &cpu.reg;
+
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(r) => match r {
+                Register::A => reg.a as i64,
+                Register::B => reg.b as i64,
+                Register::C => reg.c as i64,
+                Register::D => reg.d as i64,
+                Register::E => reg.e as i64,
+                Register::F => reg.f as i64,
+                Register::H => reg.h as i64,
+                Register::L => reg.l as i64,
+                Register::AF => reg.af() as i64,
+                Register::BC => reg.bc() as i64,
+                Register::DE => reg.de() as i64,
+                Register::HL => reg.hl() as i64,
+                Register::SP => reg.sp as i64,
+                Register::PC => reg.pc as i64,
+            },
+            Expr::Flag(flag) => reg.get_flag(*flag) as i64,
+            Expr::Bank => cpu.memory_bus.memory.bank(0x4000) as i64,
+            Expr::Ly => cpu.memory_bus.peek(0xFF44) as i64,
+            Expr::Deref(address) => cpu.memory_bus.peek(address.eval(cpu) as u16) as i64,
+            Expr::Not(e) => (e.eval(cpu) == 0) as i64,
+            Expr::Neg(e) => e.eval(cpu).wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu);
+                // short-circuit so [..] reads are only done when needed
+                match op {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => {},
+                }
+                let rhs = rhs.eval(cpu);
+
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_with_precedence() {
+        let symbols = Symbols::new();
+
+        let expr = parse("a == $12 && bank == 3 || !zf", &symbols).unwrap();
+        let a_is_12 = Expr::Binary(BinaryOp::Eq,
+            Box::new(Expr::Register(Register::A)), Box::new(Expr::Number(0x12)));
+        let bank_is_3 = Expr::Binary(BinaryOp::Eq,
+            Box::new(Expr::Bank), Box::new(Expr::Number(3)));
+        assert_eq!(expr, Expr::Binary(BinaryOp::Or,
+            Box::new(Expr::Binary(BinaryOp::And, Box::new(a_is_12), Box::new(bank_is_3))),
+            Box::new(Expr::Not(Box::new(Expr::Flag(Flag::Z))))));
+
+        let expr = parse("[hl + 1] >= %101", &symbols).unwrap();
+        assert_eq!(expr, Expr::Binary(BinaryOp::Ge,
+            Box::new(Expr::Deref(Box::new(Expr::Binary(BinaryOp::Add,
+                Box::new(Expr::Register(Register::HL)), Box::new(Expr::Number(1)))))),
+            Box::new(Expr::Number(5))));
+
+        assert!(parse("a ==", &symbols).is_err());
+        assert!(parse("[hl", &symbols).is_err());
+        assert!(parse("nope == 1", &symbols).is_err());
+    }
+
+    #[test]
+    fn wraps_instead_of_overflowing() {
+        let cpu = CPU::from_test_rom(&[]);
+
+        let expr = parse("-(0 - 9223372036854775807 - 1) == 0 - 9223372036854775807 - 1", &Symbols::new()).unwrap();
+        assert_eq!(expr.eval(&cpu), 1);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use gilrs::{Gilrs,EventType};
+
+use crate::bindings::Input;
+
+// How far a stick has to be pushed to count as a direction press
+const AXIS_THRESHOLD: f32 = 0.5;
+
+pub struct Gamepads {
+    gilrs: Gilrs,
+    // direction each axis is pushed in: -1, 0 or 1
+    axes: HashMap<gilrs::Axis, i8>,
+}
+
+impl Gamepads {
+    pub fn new() -> Option<Gamepads> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(Gamepads {
+                gilrs,
+                axes: HashMap::new(),
+            }),
+            Err(e) => {
+                eprintln!("gamepads not available: {}", e);
+                None
+            },
+        }
+    }
+
+    // Returns the inputs pressed (true) and released (false) since the last poll
+    pub fn poll(&mut self) -> Vec<(Input, bool)> {
+        let mut inputs = Vec::new();
+
+        while let Some(gilrs::Event { event, .. }) = self.gilrs.next_event() {
+            match event {
+                EventType::ButtonPressed(button, _) => inputs.push((Input::PadButton(button), true)),
+                EventType::ButtonReleased(button, _) => inputs.push((Input::PadButton(button), false)),
+                EventType::AxisChanged(axis, value, _) => {
+                    let direction = if value > AXIS_THRESHOLD {
+                        1
+                    } else if value < -AXIS_THRESHOLD {
+                        -1
+                    } else {
+                        0
+                    };
+
+                    let previous = self.axes.insert(axis, direction).unwrap_or(0);
+                    if previous == direction {
+                        continue;
+                    }
+
+                    if previous != 0 {
+                        inputs.push((Input::PadAxis(axis, previous > 0), false));
+                    }
+                    if direction != 0 {
+                        inputs.push((Input::PadAxis(axis, direction > 0), true));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        inputs
+    }
+}
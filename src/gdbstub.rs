@@ -0,0 +1,504 @@
+use std::io::{self,Read,Write};
+use std::net::{TcpListener,TcpStream};
+use std::process;
+
+use crate::cpu::CPU;
+use crate::watchpoint::{Access,Watchpoint,WatchHit,WatchKind};
+
+// GDB remote serial protocol server (https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html)
+// so gdb, lldb and IDEs can debug the game over TCP:
+//
+//   (gdb) target remote 127.0.0.1:2159
+//
+// The registers come from the target description below: af, bc, de, hl, sp and pc, 16 bits each.
+// The CPU stops at the first instruction until the debugger tells it to go on.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gbemu-rs.sm83">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 6;
+
+// Instructions between checks for gdb's interrupt (Ctrl-C) while running
+const POLL_INTERVAL: u32 = 4096;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum BreakpointKind {
+    Software,
+    Hardware,
+}
+
+// What to do after a packet
+#[derive(Debug,PartialEq,Eq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    // read but not handled yet
+    buffer: Vec<u8>,
+    no_ack: bool,
+    breakpoints: Vec<(u16, BreakpointKind)>,
+    // stop before the next instruction
+    stepping: bool,
+    interrupted: bool,
+    watch_hit: Option<WatchHit>,
+    poll_countdown: u32,
+    last_stop: String,
+    // gdb sent c or s and is waiting for a stop reply
+    resumed: bool,
+    // gdb is gone, the game runs on its own
+    pub detached: bool,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_u16(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// ADDR,LEN
+fn parse_address_length(s: &str) -> Option<(u16, u16)> {
+    let (address, length) = s.split_once(',')?;
+    Some((hex_u16(address)?, hex_u16(length)?))
+}
+
+// Registers go over the wire as little endian hex
+fn encode_register(val: u16) -> String {
+    format!("{:02x}{:02x}", val & 0xFF, val >> 8)
+}
+
+fn decode_register(s: &str) -> Option<u16> {
+    if s.len() != 4 {
+        return None;
+    }
+    let lo = u8::from_str_radix(&s[0..2], 16).ok()?;
+    let hi = u8::from_str_radix(&s[2..4], 16).ok()?;
+    Some((hi as u16) << 8 | lo as u16)
+}
+
+fn read_register(cpu: &CPU, n: usize) -> Option<u16> {
+    let reg = &cpu.reg;
+    match n {
+        0 => Some(reg.af()),
+        1 => Some(reg.bc()),
+        2 => Some(reg.de()),
+        3 => Some(reg.hl()),
+        4 => Some(reg.sp),
+        5 => Some(reg.pc),
+        _ => None,
+    }
+}
+
+fn write_register(cpu: &mut CPU, n: usize, val: u16) -> bool {
+    let reg = &mut cpu.reg;
+    match n {
+        0 => reg.set_af(val),
+        1 => reg.set_bc(val),
+        2 => reg.set_de(val),
+        3 => reg.set_hl(val),
+        4 => reg.sp = val,
+        5 => reg.pc = val,
+        _ => return false,
+    }
+    true
+}
+
+impl GdbStub {
+    pub fn listen(address: &str) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(address)?;
+        println!("waiting for gdb connection on {}", address);
+
+        let (stream, peer) = listener.accept()?;
+        println!("gdb connected from {}", peer);
+
+        GdbStub::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<GdbStub> {
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+            breakpoints: Vec::new(),
+            stepping: true,
+            interrupted: false,
+            watch_hit: None,
+            poll_countdown: POLL_INTERVAL,
+            last_stop: String::from("S05"),
+            resumed: false,
+            detached: false,
+        })
+    }
+
+    pub fn watch_hit(&mut self, hit: WatchHit) {
+        self.watch_hit = Some(hit);
+    }
+
+    // Called before every instruction
+    pub fn should_stop(&mut self, pc: u16) -> bool {
+        self.stepping
+            || self.watch_hit.is_some()
+            || self.breakpoints.iter().any(|(address, _)| *address == pc)
+            || self.poll_interrupt()
+    }
+
+    // Checks whether gdb sent an interrupt without blocking
+    fn poll_interrupt(&mut self) -> bool {
+        self.poll_countdown -= 1;
+        if self.poll_countdown > 0 {
+            return false;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buf = [0; 64];
+        let r = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false).expect("can't make the gdb connection blocking");
+
+        match r {
+            Ok(0) => self.detached = true,
+            Ok(n) => {
+                self.interrupted = buf[..n].contains(&0x03);
+                // anything else is an ack or a packet for after the stop
+                self.buffer.extend(buf[..n].iter().filter(|b| **b != 0x03));
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(_) => self.detached = true,
+        }
+
+        self.interrupted || self.detached
+    }
+
+    // Reports why the CPU stopped and serves gdb until it resumes or detaches
+    pub fn stop(&mut self, cpu: &mut CPU) {
+        if self.detached {
+            println!("gdb disconnected");
+            return;
+        }
+
+        let pc = cpu.reg.pc;
+        let breakpoint = self.breakpoints.iter().find(|(address, _)| *address == pc).map(|(_, kind)| *kind);
+
+        self.last_stop = match (self.watch_hit.take(), breakpoint) {
+            (Some(hit), _) => {
+                let kind = match hit.access {
+                    Access::Read(_) => "rwatch",
+                    Access::Write { .. } => "watch",
+                };
+                format!("T05{}:{:04x};", kind, hit.address)
+            },
+            _ if self.interrupted => String::from("S02"),
+            _ if self.stepping => String::from("S05"),
+            (None, Some(BreakpointKind::Software)) => String::from("T05swbreak:;"),
+            (None, Some(BreakpointKind::Hardware)) => String::from("T05hwbreak:;"),
+            (None, None) => String::from("S05"),
+        };
+        self.interrupted = false;
+        self.stepping = false;
+
+        if let Err(e) = self.serve(cpu) {
+            println!("gdb disconnected: {}", e);
+            self.detached = true;
+        }
+    }
+
+    fn serve(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        // gdb asks with ? on the first stop, after that it's waiting for the reply to c or s
+        if self.resumed {
+            self.resumed = false;
+            let stop = self.last_stop.clone();
+            self.send(&stop)?;
+        }
+
+        loop {
+            let packet = self.read_packet()?;
+
+            match self.handle(cpu, &packet) {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Continue => return Ok(()),
+                Action::Step => {
+                    self.stepping = true;
+                    return Ok(());
+                },
+                Action::Detach => {
+                    self.send("OK")?;
+                    println!("gdb detached");
+                    self.detached = true;
+                    return Ok(());
+                },
+                Action::Kill => process::exit(0),
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, packet: &str) -> Action {
+        let error = || Action::Reply(String::from("E01"));
+
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        match command {
+            "?" => Action::Reply(self.last_stop.clone()),
+            "g" => Action::Reply((0..REGISTERS).map(|n| encode_register(read_register(cpu, n).unwrap())).collect()),
+            "G" => {
+                if args.len() != REGISTERS * 4 {
+                    return error();
+                }
+                let values: Option<Vec<u16>> = (0..REGISTERS).map(|n| decode_register(&args[n * 4..n * 4 + 4])).collect();
+                match values {
+                    Some(values) => {
+                        for (n, val) in values.into_iter().enumerate() {
+                            write_register(cpu, n, val);
+                        }
+                        Action::Reply(String::from("OK"))
+                    },
+                    None => error(),
+                }
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| read_register(cpu, n)) {
+                Some(val) => Action::Reply(encode_register(val)),
+                None => error(),
+            },
+            "P" => {
+                let parsed = args.split_once('=')
+                    .and_then(|(n, val)| Some((usize::from_str_radix(n, 16).ok()?, decode_register(val)?)));
+                match parsed {
+                    Some((n, val)) if write_register(cpu, n, val) => Action::Reply(String::from("OK")),
+                    _ => error(),
+                }
+            },
+            "m" => match parse_address_length(args) {
+                Some((address, length)) => Action::Reply((0..length)
+                    .map(|i| format!("{:02x}", cpu.memory_bus.peek(address.wrapping_add(i))))
+                    .collect()),
+                None => error(),
+            },
+            "M" => {
+                let parsed = args.split_once(':')
+                    .and_then(|(location, data)| Some((parse_address_length(location)?, data)));
+                let (address, length, data) = match parsed {
+                    Some(((address, length), data)) if data.len() == length as usize * 2 => (address, length, data),
+                    _ => return error(),
+                };
+                for i in 0..length {
+                    let at = i as usize * 2;
+                    match u8::from_str_radix(&data[at..at + 2], 16) {
+                        Ok(val) => cpu.memory_bus.poke(address.wrapping_add(i), val),
+                        Err(_) => return error(),
+                    }
+                }
+                Action::Reply(String::from("OK"))
+            },
+            "Z" | "z" => self.breakpoint(cpu, command == "Z", args),
+            "c" | "s" => {
+                if let Some(address) = hex_u16(args) {
+                    cpu.reg.pc = address;
+                }
+                self.resumed = true;
+                if command == "c" { Action::Continue } else { Action::Step }
+            },
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" => Action::Reply(String::from("OK")),
+            _ => self.query(packet),
+        }
+    }
+
+    // Z/z TYPE,ADDR,KIND: 0 software and 1 hardware breakpoints, 2 write, 3 read and 4 access
+    // watchpoints
+    fn breakpoint(&mut self, cpu: &mut CPU, insert: bool, args: &str) -> Action {
+        let mut fields = args.split(',');
+        let parsed = (fields.next(), fields.next().and_then(hex_u16), fields.next().and_then(hex_u16));
+        let (kind, address, length) = match parsed {
+            (Some(kind), Some(address), Some(length)) => (kind, address, length),
+            _ => return Action::Reply(String::from("E01")),
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                let kind = if kind == "0" { BreakpointKind::Software } else { BreakpointKind::Hardware };
+                if insert {
+                    self.breakpoints.push((address, kind));
+                } else if let Some(i) = self.breakpoints.iter().position(|b| *b == (address, kind)) {
+                    self.breakpoints.remove(i);
+                }
+                return Action::Reply(String::from("OK"));
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Action::Reply(String::new()),
+        };
+
+        let watchpoint = Watchpoint {
+            start: address,
+            end: address.wrapping_add(length.max(1) - 1),
+            kind: watch_kind,
+            bank: None,
+        };
+        let watchpoints = &mut cpu.memory_bus.watchpoints;
+        if insert {
+            watchpoints.push(watchpoint);
+        } else if let Some(i) = watchpoints.iter().position(|w| (w.start, w.end, w.kind) == (watchpoint.start, watchpoint.end, watchpoint.kind)) {
+            watchpoints.remove(i);
+        }
+
+        Action::Reply(String::from("OK"))
+    }
+
+    fn query(&mut self, packet: &str) -> Action {
+        let reply = if packet.starts_with("qSupported") {
+            String::from("PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+")
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            String::from("OK")
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + length as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+                },
+                None => String::from("E01"),
+            }
+        } else {
+            match packet {
+                "qAttached" => String::from("1"),
+                "qC" => String::from("QC1"),
+                "qfThreadInfo" => String::from("m1"),
+                "qsThreadInfo" => String::from("l"),
+                // unsupported packets get an empty reply
+                _ => String::new(),
+            }
+        };
+
+        Action::Reply(reply)
+    }
+
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            // $DATA#CS, anything before the $ is acks or interrupts
+            if let Some(start) = self.buffer.iter().position(|b| *b == b'$') {
+                if let Some(end) = self.buffer[start..].iter().position(|b| *b == b'#').map(|end| start + end) {
+                    if self.buffer.len() >= end + 3 {
+                        let data = self.buffer[start + 1..end].to_vec();
+                        let sum = std::str::from_utf8(&self.buffer[end + 1..end + 3]).ok()
+                            .and_then(|s| u8::from_str_radix(s, 16).ok());
+                        self.buffer.drain(..end + 3);
+
+                        if sum != Some(checksum(&data)) {
+                            if !self.no_ack {
+                                self.stream.write_all(b"-")?;
+                            }
+                            continue;
+                        }
+                        if !self.no_ack {
+                            self.stream.write_all(b"+")?;
+                        }
+                        // every packet handled is ASCII, and handle slices by bytes
+                        if !data.is_ascii() {
+                            self.send("E01")?;
+                            continue;
+                        }
+                        return Ok(String::from_utf8_lossy(&data).into_owned());
+                    }
+                }
+            }
+
+            let mut buf = [0; 1024];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            self.buffer.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect() -> (GdbStub, TcpStream, CPU) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stub = GdbStub::from_stream(listener.accept().unwrap().0).unwrap();
+
+        (stub, client, CPU::from_test_rom(&[]))
+    }
+
+    fn reply(stub: &mut GdbStub, cpu: &mut CPU, packet: &str) -> Action {
+        stub.handle(cpu, packet)
+    }
+
+    #[test]
+    fn handles_packets() {
+        let (mut stub, mut client, mut cpu) = connect();
+
+        // a bad checksum is nacked and skipped
+        client.write_all(b"+$m c000,2#00$m#6d").unwrap();
+        assert_eq!(stub.read_packet().unwrap(), "m");
+        let mut acks = [0; 2];
+        client.read_exact(&mut acks).unwrap();
+        assert_eq!(&acks, b"-+");
+
+        // non-ASCII packets are acked and refused
+        client.write_all(b"$G\xc3\xa9#b3$m#6d").unwrap();
+        assert_eq!(stub.read_packet().unwrap(), "m");
+        let mut replies = [0; 9];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(&replies, b"+$E01#a6+");
+
+        cpu.reg.pc = 0x0150;
+        cpu.reg.sp = 0xFFFE;
+        cpu.reg.set_hl(0x1234);
+        assert_eq!(reply(&mut stub, &mut cpu, "g"), Action::Reply(String::from("0000000000003412feff5001")));
+        assert_eq!(reply(&mut stub, &mut cpu, "P1=cdab"), Action::Reply(String::from("OK")));
+        assert_eq!(cpu.reg.bc(), 0xABCD);
+
+        assert_eq!(reply(&mut stub, &mut cpu, "Mc000,2:beef"), Action::Reply(String::from("OK")));
+        assert_eq!(reply(&mut stub, &mut cpu, "mc000,3"), Action::Reply(String::from("beef00")));
+
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,150,1"), Action::Reply(String::from("OK")));
+        assert!(stub.should_stop(0x0150));
+        assert_eq!(reply(&mut stub, &mut cpu, "c"), Action::Continue);
+        stub.stepping = false;
+        assert!(!stub.should_stop(0x0151));
+        assert_eq!(reply(&mut stub, &mut cpu, "z0,150,1"), Action::Reply(String::from("OK")));
+        assert!(!stub.should_stop(0x0150));
+
+        assert_eq!(reply(&mut stub, &mut cpu, "Z2,c000,2"), Action::Reply(String::from("OK")));
+        assert_eq!(cpu.memory_bus.watchpoints.len(), 1);
+        assert_eq!(cpu.memory_bus.watchpoints[0].end, 0xC001);
+
+        assert_eq!(reply(&mut stub, &mut cpu, "vMustReplyEmpty"), Action::Reply(String::new()));
+    }
+}
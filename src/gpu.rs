@@ -1,3 +1,7 @@
+use std::fmt;
+
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+
 const VIDEO_RAM_SIZE: usize = 0x1FFF;
 const OAM_SIZE: usize = 0x9F;
 const VIEWPORT_PIXELS: usize = 160*144;
@@ -31,6 +35,21 @@ pub struct ObjAttributes {
     palette: bool,
 }
 
+// Positions are on screen, OAM has them offset by (8, 16)
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x {:4} y {:4} tile {:#04x} OBP{}", self.x, self.y, self.tile,
+               if self.attributes.palette { 1 } else { 0 })?;
+        for (name, set) in [("x-flip", self.attributes.x_flip), ("y-flip", self.attributes.y_flip),
+                            ("behind-bg", self.attributes.bg_win_over_obj)] {
+            if set {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Obj {
     pub fn new() -> Obj {
         Obj {
@@ -66,6 +85,9 @@ pub struct GPU {
     obj1_palette: Palette,
 
     lcd_status: LCDStatus,
+
+    // set when the PPU enters VBlank, cleared by whoever is waiting for the frame
+    pub frame_complete: bool,
 }
 
 #[derive(Debug,Clone,Copy)]
@@ -110,6 +132,19 @@ impl From<LCDStatus> for u8 {
     }
 }
 
+impl fmt::Display for LCDStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x} mode {:?}, LYC=LY {}, interrupts:", u8::from(*self), self.mode, self.lyc_equals_ly)?;
+        for (name, enabled) in [("LYC", self.lyc_equals_ly_int), ("OAM", self.oam_int),
+                                ("VBlank", self.vblank_int), ("HBlank", self.hblank_int)] {
+            if enabled {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone,Copy,PartialEq)]
 pub enum GPUInterrupts {
     None,
@@ -173,6 +208,21 @@ impl From<LCDC> for u8 {
     }
 }
 
+impl fmt::Display for LCDC {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x} LCD {}, window {} (map {}), BG map {}, tiles {}, objects {} (8x{}), BG/window {}",
+               u8::from(*self),
+               if self.lcd_enable { "on" } else { "off" },
+               if self.window_enable { "on" } else { "off" },
+               if self.window_tilemap { "0x9c00" } else { "0x9800" },
+               if self.bg_tilemap { "0x9c00" } else { "0x9800" },
+               if self.bg_window_addressing_mode { "0x8000" } else { "0x8800" },
+               if self.obj_enable { "on" } else { "off" },
+               if self.obj_size { 16 } else { 8 },
+               if self.bg_window_priority { "on" } else { "off" })
+    }
+}
+
 #[derive(PartialEq,Debug,Clone,Copy)]
 pub enum Color {
     White = 255,
@@ -278,6 +328,12 @@ impl From<Palette> for u8 {
     }
 }
 
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x} ({:?}, {:?}, {:?}, {:?})", u8::from(*self), self.0, self.1, self.2, self.3)
+    }
+}
+
 #[derive(Debug,Clone,Copy)]
 pub enum GPUMode {
     HBlank,
@@ -323,27 +379,41 @@ impl GPU {
             bg_palette: Palette::new(),
             obj0_palette: Palette::new(),
             obj1_palette: Palette::new(),
+
+            frame_complete: false,
         }
     }
 
+    // For gbdb's info lcd
+    pub fn lcd_info(&self) -> String {
+        format!("LCDC: {}\nSTAT: {}\nLY: {} LYC: {} mode clock: {}\nSCX: {} SCY: {}\nBGP:  {}\nOBP0: {}\nOBP1: {}",
+                self.lcdc, self.lcd_status, self.ly, self.lyc, self.mode_clock, self.scx, self.scy,
+                self.bg_palette, self.obj0_palette, self.obj1_palette)
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc.lcd_enable
+    }
+
     pub fn step(&mut self, cycles: u32) -> GPUInterrupts {
         let mut interrupts_requested = GPUInterrupts::None;
         if !self.lcdc.lcd_enable {
             return interrupts_requested;
         }
 
+        // the cycles past a mode change count towards the next mode, or frames get longer
         self.mode_clock += cycles;
 
         match self.lcd_status.mode {
             GPUMode::OAMRead => {
                 if self.mode_clock >= 80 {
-                    self.mode_clock = 0;
+                    self.mode_clock -= 80;
                     self.lcd_status.mode = GPUMode::VRAMRead;
                 }
             },
             GPUMode::VRAMRead => {
                 if self.mode_clock >= 172 {
-                    self.mode_clock = 0;
+                    self.mode_clock -= 172;
                     self.lcd_status.mode = GPUMode::HBlank;
 
                     if self.lcd_status.hblank_int {
@@ -357,11 +427,12 @@ impl GPU {
             },
             GPUMode::HBlank => {
                 if self.mode_clock >= 204 {
-                    self.mode_clock = 0;
+                    self.mode_clock -= 204;
                     self.ly += 1;
 
                     if self.ly == 143 {
                         self.lcd_status.mode = GPUMode::VBlank;
+                        self.frame_complete = true;
                         interrupts_requested.add(GPUInterrupts::VBlank);
                         if self.lcd_status.vblank_int {
                             interrupts_requested.add(GPUInterrupts::LCDStat);
@@ -373,7 +444,7 @@ impl GPU {
             },
             GPUMode::VBlank => {
                 if self.mode_clock >= 456 {
-                    self.mode_clock = 0;
+                    self.mode_clock -= 456;
                     self.ly += 1;
 
                     if self.ly > 153 {
@@ -615,3 +686,69 @@ impl GPU {
         }
     }
 }
+
+// Tiles and objects are decoded from VRAM and OAM so they're rebuilt on load
+impl SaveState for GPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.video_ram);
+        w.bytes(&self.oam);
+        for pixel in self.canvas_buffer.iter() {
+            w.u32(*pixel);
+        }
+
+        w.u32(self.mode_clock);
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(self.lcdc.into());
+        w.u8(self.bg_palette.into());
+        w.u8(self.obj0_palette.into());
+        w.u8(self.obj1_palette.into());
+
+        w.u8(self.lcd_status.into());
+        w.bool(self.lcd_status.lyc_equals_ly);
+        w.u8(match self.lcd_status.mode {
+            GPUMode::HBlank => 0,
+            GPUMode::VBlank => 1,
+            GPUMode::OAMRead => 2,
+            GPUMode::VRAMRead => 3,
+        });
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.video_ram)?;
+        r.bytes(&mut self.oam)?;
+        for pixel in self.canvas_buffer.iter_mut() {
+            *pixel = r.u32()?;
+        }
+
+        self.mode_clock = r.u32()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.ly = r.u8()?;
+        self.lyc = r.u8()?;
+        self.lcdc = r.u8()?.into();
+        self.bg_palette = r.u8()?.into();
+        self.obj0_palette = r.u8()?.into();
+        self.obj1_palette = r.u8()?.into();
+
+        self.lcd_status = r.u8()?.into();
+        self.lcd_status.lyc_equals_ly = r.bool()?;
+        self.lcd_status.mode = match r.u8()? {
+            0 => GPUMode::HBlank,
+            1 => GPUMode::VBlank,
+            2 => GPUMode::OAMRead,
+            _ => GPUMode::VRAMRead,
+        };
+
+        for address in (0..VIDEO_RAM_SIZE).step_by(2) {
+            self.update_tile(address);
+        }
+        for address in 0..=OAM_SIZE {
+            self.update_object(address, self.oam[address]);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use crate::callstack::CallStack;
+use crate::cpu::CPU;
+use crate::rewind::{self,Delta};
+use crate::save_state;
+
+// Checkpoints for gbdb's reverse execution. One is taken at the start of every frame and every
+// time gbdb resumes, since it could have changed anything. Going back restores the closest
+// checkpoint before the target and runs forward from it, which lands on the same state because
+// emulation is deterministic: input only changes between frames. A link cable partner isn't
+// though, so what it sends isn't replayed.
+//
+// Only every KEYFRAME_INTERVAL-th state is kept whole, the ones in between are deltas against the
+// last whole one before them (see rewind), so restoring any checkpoint takes a single decode.
+
+// One per frame is about 10 seconds
+const CAPACITY: usize = 600;
+const KEYFRAME_INTERVAL: usize = 60;
+
+enum State {
+    Full(Vec<u8>),
+    Delta(Delta),
+}
+
+struct Checkpoint {
+    // CPU::instructions when it was taken
+    instruction: u64,
+    state: State,
+    call_stack: CallStack,
+}
+
+pub struct History {
+    // oldest first
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            checkpoints: VecDeque::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    // Index of the latest checkpoint at or before instruction
+    fn latest_before(&self, instruction: u64) -> Option<usize> {
+        self.checkpoints.iter().rposition(|c| c.instruction <= instruction)
+    }
+
+    // Index and state of the latest whole state at or before index
+    fn keyframe(&self, index: usize) -> Option<(usize, &[u8])> {
+        self.checkpoints.range(..=index).enumerate().rev().find_map(|(i, c)| match &c.state {
+            State::Full(state) => Some((i, state.as_slice())),
+            State::Delta(_) => None,
+        })
+    }
+
+    fn state(&self, index: usize) -> Vec<u8> {
+        match &self.checkpoints[index].state {
+            State::Full(state) => state.clone(),
+            State::Delta(delta) => {
+                let (_, keyframe) = self.keyframe(index).expect("history starts with a whole state");
+                rewind::decode(delta, keyframe)
+            },
+        }
+    }
+}
+
+pub fn checkpoint(cpu: &mut CPU) {
+    let state = save_state::save(cpu);
+    let history = &mut cpu.history;
+
+    // after going back the ones ahead are a future that may not happen anymore
+    while history.checkpoints.back().is_some_and(|c| c.instruction >= cpu.instructions) {
+        history.checkpoints.pop_back();
+    }
+
+    let len = history.checkpoints.len();
+    let state = match len.checked_sub(1).and_then(|last| history.keyframe(last)) {
+        Some((i, keyframe)) if len - i < KEYFRAME_INTERVAL => State::Delta(rewind::encode(&state, keyframe)),
+        _ => State::Full(state),
+    };
+
+    history.checkpoints.push_back(Checkpoint {
+        instruction: cpu.instructions,
+        state,
+        call_stack: cpu.call_stack.clone(),
+    });
+
+    // the deltas against the oldest whole state go with it
+    if history.checkpoints.len() > CAPACITY {
+        history.checkpoints.pop_front();
+        while history.checkpoints.front().is_some_and(|c| matches!(c.state, State::Delta(_))) {
+            history.checkpoints.pop_front();
+        }
+    }
+}
+
+fn restore(cpu: &mut CPU, index: usize) {
+    // loading a state clears the history, it's still good here
+    let history = mem::replace(&mut cpu.history, History::new());
+
+    let checkpoint = &history.checkpoints[index];
+    save_state::load(cpu, &history.state(index)).expect("can't restore checkpoint");
+    cpu.call_stack = checkpoint.call_stack.clone();
+    cpu.instructions = checkpoint.instruction;
+
+    cpu.history = history;
+}
+
+// Runs until CPU::instructions is until. Returns the instructions gbdb would have stopped at, at a
+// breakpoint before running one or after one hit a watchpoint.
+fn replay(cpu: &mut CPU, until: u64) -> Vec<u64> {
+    let mut stops = Vec::new();
+
+    // it all went out the first time around
+    let device = cpu.memory_bus.serial.disconnect();
+    let output = cpu.memory_bus.serial.take_output();
+    cpu.replaying = true;
+
+    while cpu.instructions < until {
+        if cpu.breakpoints.iter().any(|bp| bp.at(cpu) && bp.condition_holds(cpu)) {
+            stops.push(cpu.instructions);
+        }
+
+        cpu.step();
+
+        if cpu.memory_bus.take_watch_hit().is_some() {
+            stops.push(cpu.instructions);
+        }
+    }
+
+    cpu.replaying = false;
+    cpu.memory_bus.serial.connect(device);
+    cpu.memory_bus.serial.set_output(output);
+    // a VBlank on the way would end the next frame right away
+    cpu.memory_bus.gpu.frame_complete = false;
+
+    stops
+}
+
+// Goes back one instruction
+pub fn reverse_step(cpu: &mut CPU) -> Result<(), String> {
+    let target = match cpu.instructions.checked_sub(1) {
+        Some(target) => target,
+        None => return Err(String::from("nothing ran yet")),
+    };
+    let index = cpu.history.latest_before(target).ok_or("no history that far back")?;
+
+    restore(cpu, index);
+    replay(cpu, target);
+
+    Ok(())
+}
+
+// Goes back to the last breakpoint or watchpoint hit. Returns false if there was none and it
+// stopped at the oldest checkpoint instead.
+pub fn reverse_continue(cpu: &mut CPU) -> Result<bool, String> {
+    let mut end = cpu.instructions;
+    let mut index = match end.checked_sub(1).and_then(|target| cpu.history.latest_before(target)) {
+        Some(index) => index,
+        None => return Err(String::from("no history that far back")),
+    };
+
+    loop {
+        let start = cpu.history.checkpoints[index].instruction;
+
+        restore(cpu, index);
+        let stops = replay(cpu, end);
+
+        // the one at end is where we came from or was found in the next segment already
+        if let Some(&stop) = stops.iter().rev().find(|&&stop| stop < end) {
+            restore(cpu, index);
+            replay(cpu, stop);
+            return Ok(true);
+        }
+
+        if index == 0 {
+            restore(cpu, 0);
+            return Ok(false);
+        }
+
+        end = start;
+        index -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_back_to_watchpoint_hits() {
+        // ld a, $42; ld [$c000], a; inc a; ld [$c001], a; jr -2
+        let mut rom = vec![0; 0x100];
+        rom.extend_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x3C, 0xEA, 0x01, 0xC0, 0x18, 0xFA]);
+        let mut cpu = CPU::from_test_rom(&rom);
+
+        checkpoint(&mut cpu);
+        cpu.memory_bus.watchpoints.push(crate::watchpoint::Watchpoint {
+            start: 0xC000,
+            end: 0xC000,
+            kind: crate::watchpoint::WatchKind::Write,
+            bank: None,
+        });
+        for _ in 0..9 {
+            cpu.step();
+        }
+        _ = cpu.memory_bus.take_watch_hit();
+        let a = cpu.reg.a;
+
+        // as if a VBlank came on the way
+        cpu.memory_bus.gpu.frame_complete = true;
+        reverse_step(&mut cpu).unwrap();
+        assert_eq!(cpu.instructions, 8);
+        assert!(!cpu.memory_bus.gpu.frame_complete);
+        assert_eq!(cpu.reg.a, a - 1);
+
+        // right after the write to 0xC000
+        assert!(reverse_continue(&mut cpu).unwrap());
+        assert_eq!(cpu.instructions, 2);
+        assert_eq!(cpu.reg.pc, 0x105);
+        assert_eq!(cpu.memory_bus.peek(0xC000), 0x42);
+
+        assert!(!reverse_continue(&mut cpu).unwrap());
+        assert_eq!(cpu.instructions, 0);
+        assert_eq!(cpu.reg.pc, 0x100);
+        assert!(reverse_continue(&mut cpu).is_err());
+    }
+
+    #[test]
+    fn keeps_deltas_between_whole_states() {
+        // inc a; jr -3
+        let mut rom = vec![0; 0x100];
+        rom.extend_from_slice(&[0x3C, 0x18, 0xFD]);
+        let mut cpu = CPU::from_test_rom(&rom);
+
+        let mut states = Vec::new();
+        for _ in 0..KEYFRAME_INTERVAL + 5 {
+            cpu.step();
+            checkpoint(&mut cpu);
+            states.push(save_state::save(&cpu));
+        }
+
+        assert!(matches!(cpu.history.checkpoints[1].state, State::Delta(_)));
+        assert!(matches!(cpu.history.checkpoints[KEYFRAME_INTERVAL].state, State::Full(_)));
+        for (i, state) in states.iter().enumerate() {
+            assert!(cpu.history.state(i) == *state, "checkpoint {} differs", i);
+        }
+    }
+}
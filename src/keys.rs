@@ -1,4 +1,35 @@
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+}
+
+impl Button {
+    // row and bit of the input line the button pulls low
+    fn line(&self) -> (usize, u8) {
+        match self {
+            Button::A => (0, 0x1),
+            Button::B => (0, 0x2),
+            Button::Select => (0, 0x4),
+            Button::Start => (0, 0x8),
+            Button::Right => (1, 0x1),
+            Button::Left => (1, 0x2),
+            Button::Up => (1, 0x4),
+            Button::Down => (1, 0x8),
+        }
+    }
+}
+
 pub struct Keys {
+    // 0: buttons, 1: directions. A 0 bit means pressed.
     rows: [u8; 2],
     column: u8
 }
@@ -11,56 +42,119 @@ impl Keys {
         }
     }
 
-    pub fn read_byte(&self) -> u8 {
-        match self.column {
-            0x10 => {
-                self.rows[0]
-            },
-            0x20 => self.rows[1],
-            _ => { 0 },
+    // The 4 input lines as seen by the CPU: a row pulls its lines low when it's selected (its
+    // select bit is 0) and a key is pressed. With both rows selected they're combined.
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+
+        if self.column & 0x20 == 0 {
+            lines &= self.rows[0];
         }
+        if self.column & 0x10 == 0 {
+            lines &= self.rows[1];
+        }
+
+        lines
     }
 
-    pub fn write_byte(&mut self, val: u8) {
+    pub fn read_byte(&self) -> u8 {
+        // bits 6-7 are unused and read as 1
+        0xC0 | self.column | self.lines()
+    }
+
+    // Returns true if the joypad interrupt should be requested
+    pub fn write_byte(&mut self, val: u8) -> bool {
+        let lines = self.lines();
         self.column = val & 0x30;
+
+        self.falling_edge(lines)
     }
 
-    pub fn key_down(&mut self, k: &minifb::Key) {
-        match k {
-            minifb::Key::Up => self.rows[1] &= 0xB,
-            minifb::Key::Down => self.rows[1] &= 0x7,
-            minifb::Key::Left => self.rows[1] &= 0xD,
-            minifb::Key::Right => self.rows[1] &= 0xE,
-            // B
-            minifb::Key::A => self.rows[0] &= 0xD,
-            // A
-            minifb::Key::S => {
-                self.rows[0] &= 0xE;
-            },
-            // Start
-            minifb::Key::G => self.rows[0] &= 0x7,
-            // Select
-            minifb::Key::H => self.rows[0] &= 0xB,
-            _ => {},
-        }
+    // The joypad interrupt is requested when any input line goes from high to low
+    fn falling_edge(&self, old_lines: u8) -> bool {
+        old_lines & !self.lines() != 0
     }
 
-    pub fn key_up(&mut self, k: &minifb::Key) {
-        match k {
-            minifb::Key::Up => self.rows[1] |= 0x4,
-            minifb::Key::Down => self.rows[1] |= 0x8,
-            minifb::Key::Left => self.rows[1] |= 0x2,
-            minifb::Key::Right => self.rows[1] |= 0x1,
-            // B
-            minifb::Key::A => self.rows[0] |= 0x2,
-            // A
-            minifb::Key::S => self.rows[0] |= 0x1,
-            // Start
-            minifb::Key::G => self.rows[0] |= 0x8,
-            // Select
-            minifb::Key::H => self.rows[0] |= 0x4,
-            _ => {},
-        }
+    // Returns true if the joypad interrupt should be requested
+    pub fn press(&mut self, button: Button) -> bool {
+        let lines = self.lines();
+        let (row, bit) = button.line();
+
+        self.rows[row] &= !bit;
+
+        self.falling_edge(lines)
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let (row, bit) = button.line();
+
+        self.rows[row] |= bit;
+    }
+
+    // All 8 buttons in one byte, buttons in the low nibble and directions in the high one
+    pub fn state(&self) -> u8 {
+        self.rows[0] | self.rows[1] << 4
+    }
+
+    // Returns true if the joypad interrupt should be requested
+    pub fn set_state(&mut self, state: u8) -> bool {
+        let lines = self.lines();
+
+        self.rows = [state & 0x0F, state >> 4];
+
+        self.falling_edge(lines)
+    }
+}
+
+impl SaveState for Keys {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.rows);
+        w.u8(self.column);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.rows)?;
+        self.column = r.u8()?;
+
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_rows_are_combined() {
+        let mut keys = Keys::new();
+
+        keys.press(Button::Up);
+        keys.press(Button::A);
+
+        keys.write_byte(0x30);
+        assert_eq!(keys.read_byte(), 0xFF);
+
+        keys.write_byte(0x10);
+        assert_eq!(keys.read_byte(), 0xDE);
+
+        keys.write_byte(0x20);
+        assert_eq!(keys.read_byte(), 0xEB);
+
+        keys.write_byte(0x00);
+        assert_eq!(keys.read_byte(), 0xCA);
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low() {
+        let mut keys = Keys::new();
+        keys.write_byte(0x10);
+
+        assert!(keys.press(Button::A));
+        // already low
+        assert!(!keys.press(Button::A));
+        // row not selected
+        assert!(!keys.press(Button::Up));
+        // selecting the directions pulls Up low
+        assert!(keys.write_byte(0x00));
+    }
+}
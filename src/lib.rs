@@ -1,10 +1,10 @@
-use std::env;
+use std::{env,fs,process};
 
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::time::{Instant,Duration};
-use std::thread::sleep;
-use minifb::{Window,Key,WindowOptions,Scale};
+use std::path::PathBuf;
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
+use minifb::{Window,WindowOptions,Scale};
 
 mod registers;
 mod memory;
@@ -13,20 +13,92 @@ mod cpu;
 mod gpu;
 mod keys;
 mod debug;
+mod serial;
+mod link;
+mod printer;
+mod bindings;
+mod save_state;
+mod rewind;
+mod movie;
+mod pacing;
+mod trace;
+mod disassembler;
+mod symbols;
+mod watchpoint;
+mod expr;
+mod callstack;
+mod gdbstub;
+mod screenshot;
+mod script;
+mod history;
+mod catchpoint;
+mod session;
+#[cfg(feature = "gamepad")]
+mod gamepad;
 
 use cpu::CPU;
+use link::TcpLink;
+use printer::Printer;
+use bindings::{Action,Bindings,Hotkey,Input};
+use rewind::Rewind;
+use movie::Movie;
+use keys::Keys;
+use pacing::{Pacer,FRAME_DURATION};
+use trace::Tracer;
+use gdbstub::GdbStub;
+use symbols::Symbols;
+#[cfg(feature = "gamepad")]
+use gamepad::Gamepads;
+
+pub use serial::SerialOutput;
+pub use pacing::Pacing;
+pub use trace::Trigger;
 
 const NUMBER_OF_PIXELS: usize = 160*144 + 1;
 
 pub struct Emulator {
     cpu: CPU,
     window: Window,
+    pacer: Pacer,
+    bindings: Bindings,
+    #[cfg(feature = "gamepad")]
+    gamepads: Option<Gamepads>,
+
+    quit: bool,
+    paused: bool,
+    // run one frame and stay paused
+    frame_advance: bool,
+    fast_forward: bool,
+    fast_forward_speed: f64,
+    slow_motion: bool,
+    slow_motion_speed: f64,
+    title: String,
+    slot: u8,
+    // one snapshot per frame, None if rewinding is disabled
+    rewind: Option<Rewind>,
+    rewinding: bool,
+
+    // While recording or replaying a movie button presses go to held, which is applied to the
+    // joypad at the start of every frame
+    movie: Option<Movie>,
+    held: Keys,
+    // a script's frame() returned for a hook partway through the frame
+    script_mid_frame: bool,
+
+    // run before the game starts
+    script_path: Option<String>,
 }
 
 
-const ONE_SECOND_IN_NANOS: usize = 1000000000;
-const ONE_SECOND_IN_CYCLES: usize = 4190000;
-const ONE_FRAME_IN_CYCLES: usize = 70224;
+const TITLE: &str = "gbemu-rs";
+
+const DEFAULT_FAST_FORWARD_SPEED: f64 = 4.0;
+const DEFAULT_SLOW_MOTION_SPEED: f64 = 0.25;
+
+const SAVE_STATE_SLOTS: u8 = 10;
+
+const FRAMES_PER_SECOND: usize = 60;
+const DEFAULT_REWIND_SECONDS: usize = 60;
 
 pub struct KeyData {
     key: minifb::Key,
@@ -62,23 +134,127 @@ impl Emulator {
         let mut window_options = WindowOptions::default();
         window_options.scale = Scale::X8;
 
+        let mut cpu = CPU::new(config.rom_path, config.boot_rom_path, config.debug);
+
+        // a device is talking binary, echoing it to the terminal is only useful without one
+        let serial_output = match config.serial_output {
+            Some(output) => output,
+            None if config.link.is_some() => SerialOutput::Discard,
+            None => SerialOutput::Stdout,
+        };
+
+        match config.link {
+            Some(Link::Listen(address)) => {
+                let link = TcpLink::listen(&address).expect("can't set up link cable");
+                cpu.memory_bus.serial.connect(Box::new(link));
+            },
+            Some(Link::Connect(address)) => {
+                let link = TcpLink::connect(&address).expect("can't set up link cable");
+                cpu.memory_bus.serial.connect(Box::new(link));
+            },
+            Some(Link::Printer(output_dir)) => {
+                cpu.memory_bus.serial.connect(Box::new(Printer::new(&output_dir)));
+            },
+            None => {},
+        }
+
+        cpu.memory_bus.serial.set_output(serial_output);
+
+        match config.symbols_path {
+            Some(path) => cpu.symbols = Symbols::from_file(&path).expect("can't load symbols"),
+            None => {
+                // game.gb -> game.sym, as RGBDS names them
+                let path = PathBuf::from(&cpu.rom_path).with_extension("sym");
+                if path.exists() {
+                    match Symbols::from_file(&path.to_string_lossy()) {
+                        Ok(symbols) => cpu.symbols = symbols,
+                        Err(e) => eprintln!("can't load symbols: {}", e),
+                    }
+                }
+            },
+        }
+
+        if let Some(path) = config.trace_path {
+            let mut tracer = Tracer::new(&path, config.trace_start, config.trace_stop)
+                .expect("can't create trace file");
+            tracer.symbols = config.trace_symbols;
+            cpu.tracer = Some(tracer);
+        }
+
+        if let Some(address) = config.gdb_address {
+            cpu.gdb = Some(GdbStub::listen(&address).expect("can't start gdb server"));
+        }
+
+        if config.debug {
+            if let Some(ret) = session::start(&mut cpu, config.gbdbinit_path.as_deref()) {
+                cpu.resume(ret);
+            }
+
+            // where the cartridge takes over, after the boot ROM
+            if config.break_at_entry && !cpu.breakpoints.iter().any(|bp| bp.address == 0x100) {
+                cpu.breakpoints.push(debug::Breakpoint::new(0x100));
+            }
+        }
+
+        let movie = match config.movie {
+            Some(MovieMode::Record(path)) => Some(Movie::record(&path, &cpu).expect("can't record movie")),
+            Some(MovieMode::Replay(path)) => Some(Movie::replay(&path, &mut cpu).expect("can't replay movie")),
+            None => None,
+        };
+        cpu.movie_active = movie.is_some();
+
+        let bindings = match config.bindings_path {
+            Some(path) => Bindings::from_file(&path).expect("can't load key bindings"),
+            None => Bindings::new(),
+        };
+
+        let mut window = Window::new(
+            TITLE,
+            160,
+            144,
+            window_options)
+            .expect("failed to create window");
+        let pacer = Pacer::new(config.pacing, &mut window);
+
         Emulator {
-            cpu: CPU::new(config.rom_path, config.boot_rom_path, config.debug),
-            window: Window::new(
-                "gbemu-rs",
-                160,
-                144,
-                window_options)
-                .expect("failed to create window"),
+            cpu,
+            window,
+            pacer,
+            bindings,
+            #[cfg(feature = "gamepad")]
+            gamepads: Gamepads::new(),
+            quit: false,
+            paused: false,
+            frame_advance: false,
+            fast_forward: false,
+            fast_forward_speed: config.fast_forward_speed,
+            slow_motion: false,
+            slow_motion_speed: config.slow_motion_speed,
+            title: TITLE.to_string(),
+            slot: 0,
+            rewind: match config.rewind_seconds {
+                0 => None,
+                seconds => Some(Rewind::new(seconds * FRAMES_PER_SECOND)),
+            },
+            rewinding: false,
+            movie,
+            held: Keys::new(),
+            script_mid_frame: false,
+            script_path: config.script_path,
         }
     }
 
+    pub fn set_serial_output(&mut self, output: SerialOutput) {
+        self.cpu.memory_bus.serial.set_output(output);
+    }
+
+    // Bytes sent through the serial port so far, when it's captured to SerialOutput::Buffer
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.memory_bus.serial.output()
+    }
+
     pub fn run(&mut self) {
         let mut window_buffer: [u32; NUMBER_OF_PIXELS+1] = [0; NUMBER_OF_PIXELS+1];
-        let mut cycles_elapsed_in_frame = 0usize;
-        let mut now = Instant::now();
-
-        self.window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
         let keys_data = KeyVec::new(RefCell::new(Vec::new()));
 
@@ -86,57 +262,370 @@ impl Emulator {
 
         self.window.set_input_callback(keys_callback);
 
-        while self.window.is_open() && !self.window.is_key_down(Key::Escape) {
-            if self.window.is_key_down(Key::Space) {
-                self.cpu.stop_at_next_frame = true;
+        if let Some(path) = self.script_path.take() {
+            if let Err(e) = script::run(self, &path) {
+                eprintln!("script failed: {}", e);
+                process::exit(1);
+            }
+        }
+
+        while self.window.is_open() && !self.quit {
+            // while paused or rewinding keep going at one frame per frame so the window stays
+            // responsive
+            let mut frames = 1;
+            let mut speed = 1.0;
+
+            if self.paused {
+                if self.frame_advance {
+                    self.emulate_frame();
+                    self.frame_advance = false;
+                }
+            } else if self.rewinding {
+                self.rewind_frame();
+            } else {
+                speed = self.speed();
+                frames = self.emulate_frames(speed);
             }
 
-            let time_delta = now.elapsed().subsec_nanos();
-            now = Instant::now();
-            let delta = time_delta as f64 / ONE_SECOND_IN_NANOS as f64;
-            let cycles_to_run = delta * ONE_SECOND_IN_CYCLES as f64;
+            self.update_title();
+            self.present_frame(&mut window_buffer);
+            self.handle_inputs(&keys_data);
+            self.pacer.wait(frames, speed);
+        }
 
-            let mut cycles_elapsed = 0;
+        if let Some(movie) = self.movie.as_ref().filter(|movie| movie.recording()) {
+            println!("saved movie {} ({} frames)", movie.path(), movie.frame_count());
+        }
+    }
+
+    fn speed(&self) -> f64 {
+        if self.fast_forward {
+            self.fast_forward_speed
+        } else if self.slow_motion {
+            self.slow_motion_speed
+        } else {
+            1.0
+        }
+    }
 
-            while cycles_elapsed <= cycles_to_run as usize {
-                cycles_elapsed += self.cpu.step() as usize;
+    // Emulates the frames to show in the time of one and returns how many it ran. Faster speeds
+    // run several frames per presented one, uncapped runs as many as fit.
+    fn emulate_frames(&mut self, speed: f64) -> usize {
+        let mut frames = 0;
+
+        if speed.is_infinite() {
+            let start = Instant::now();
+            while start.elapsed() < FRAME_DURATION {
+                self.emulate_frame();
+                frames += 1;
+            }
+        } else {
+            for _ in 0..speed.max(1.0).round() as usize {
+                self.emulate_frame();
+                frames += 1;
             }
-            cycles_elapsed_in_frame += cycles_elapsed;
+        }
 
-            if cycles_elapsed_in_frame >= ONE_FRAME_IN_CYCLES {
-                if self.cpu.stop_at_next_frame {
-                    self.cpu.drop_to_shell();
-                }
+        frames
+    }
 
-                for (i, pixel) in self.cpu.pixel_buffer().enumerate() {
-                    window_buffer[i] = *pixel;
-                }
-                self.window.update_with_buffer(&window_buffer[..], 160, 144).unwrap();
+    fn update_title(&mut self) {
+        let status = if self.paused {
+            String::from("paused")
+        } else if self.rewinding {
+            String::from("rewinding")
+        } else {
+            match self.speed() {
+                speed if speed.is_infinite() => String::from("uncapped"),
+                speed if speed != 1.0 => format!("{}x", speed),
+                _ => String::new(),
+            }
+        };
+
+        let title = if status.is_empty() {
+            TITLE.to_string()
+        } else {
+            format!("{} [{}]", TITLE, status)
+        };
+
+        if title != self.title {
+            self.window.set_title(&title);
+            self.title = title;
+        }
+    }
+
+    fn emulate_frame(&mut self) {
+        if self.movie.is_some() {
+            self.apply_movie_input();
+        }
+
+        self.run_frame();
+
+        // rewinding would desync the movie so there's nothing to record
+        if let (Some(rewind), None) = (&mut self.rewind, &self.movie) {
+            rewind.push(save_state::save(&self.cpu));
+        }
+    }
+
+    // Runs the CPU until the PPU enters VBlank, so every frame we present is complete
+    fn run_frame(&mut self) {
+        self.cpu.run_frame();
+
+        if self.cpu.stop_at_next_frame {
+            self.cpu.drop_to_shell();
+        }
+    }
+
+    // Sets the joypad to the movie's state for the coming frame. Once a replay is over the
+    // player takes over.
+    fn apply_movie_input(&mut self) {
+        let movie = match &mut self.movie {
+            Some(movie) => movie,
+            None => return,
+        };
 
-                let mut keys = keys_data.borrow_mut();
+        let joypad = match movie.frame(self.held.state()) {
+            Some(joypad) => joypad,
+            None => {
+                println!("replay of {} finished", movie.path());
+                self.movie = None;
+                self.cpu.movie_active = false;
+                self.held.state()
+            },
+        };
+
+        if self.cpu.memory_bus.joypad.set_state(joypad) {
+            self.cpu.memory_bus.interrupt_flag.joypad = true;
+        }
+    }
+
+    fn rewind_frame(&mut self) {
+        let state = match self.rewind.as_mut().and_then(|rewind| rewind.pop()) {
+            Some(state) => state,
+            None => return,
+        };
+
+        if let Err(e) = save_state::load(&mut self.cpu, &state) {
+            eprintln!("can't rewind: {}", e);
+        }
+    }
 
-                for k in keys.iter() {
-                    if k.state {
-                        self.cpu.memory_bus.joypad.key_down(&k.key);
+    fn present_frame(&mut self, window_buffer: &mut [u32]) {
+        for (i, pixel) in self.cpu.pixel_buffer().enumerate() {
+            window_buffer[i] = *pixel;
+        }
+        self.window.update_with_buffer(window_buffer, 160, 144).unwrap();
+    }
+
+    fn handle_inputs(&mut self, keys_data: &KeyVec) {
+        let mut inputs: Vec<(Input, bool)> = keys_data.borrow_mut()
+            .drain(..)
+            .map(|k| (Input::Key(k.key), k.state))
+            .collect();
+        inputs.extend(self.poll_gamepads());
+
+        for (input, pressed) in inputs {
+            match self.bindings.action(&input) {
+                Some(Action::Button(button)) if self.movie.is_some() => {
+                    if pressed {
+                        self.held.press(button);
                     } else {
-                        self.cpu.memory_bus.joypad.key_up(&k.key);
+                        self.held.release(button);
                     }
-                }
+                },
+                Some(Action::Button(button)) => {
+                    if pressed {
+                        if self.cpu.memory_bus.joypad.press(button) {
+                            self.cpu.memory_bus.interrupt_flag.joypad = true;
+                        }
+                    } else {
+                        self.cpu.memory_bus.joypad.release(button);
+                    }
+                },
+                Some(Action::Hotkey(hotkey)) => self.handle_hotkey(hotkey, pressed),
+                None => {},
+            }
+        }
+    }
 
-                keys.clear();
+    #[cfg(feature = "gamepad")]
+    fn poll_gamepads(&mut self) -> Vec<(Input, bool)> {
+        match &mut self.gamepads {
+            Some(gamepads) => gamepads.poll(),
+            None => Vec::new(),
+        }
+    }
 
-                cycles_elapsed_in_frame = 0;
-            } else {
-                sleep(Duration::from_nanos(2))
-            }
+    #[cfg(not(feature = "gamepad"))]
+    fn poll_gamepads(&mut self) -> Vec<(Input, bool)> {
+        Vec::new()
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey, pressed: bool) {
+        // fast-forward and rewind are held, the rest act on press
+        match hotkey {
+            Hotkey::FastForward => {
+                self.fast_forward = pressed;
+                return;
+            },
+            Hotkey::Rewind => {
+                self.rewinding = pressed && self.movie.is_none();
+                return;
+            },
+            Hotkey::SlowMotion => {
+                self.slow_motion = pressed;
+                return;
+            },
+            _ => {},
+        }
+
+        if !pressed {
+            return;
+        }
+
+        match hotkey {
+            Hotkey::Quit => self.quit = true,
+            Hotkey::Pause => self.paused = !self.paused,
+            Hotkey::FrameAdvance => {
+                self.paused = true;
+                self.frame_advance = true;
+            },
+            Hotkey::Debugger => self.cpu.stop_at_next_frame = true,
+            Hotkey::Screenshot => match self.screenshot() {
+                Ok(path) => println!("saved screenshot {}", path),
+                Err(e) => eprintln!("can't save screenshot: {}", e),
+            },
+            Hotkey::SaveState => match save_state::save_slot(&self.cpu, self.slot) {
+                Ok(path) => println!("saved state {}", path.display()),
+                Err(e) => eprintln!("can't save state: {}", e),
+            },
+            // the movie wouldn't match what happened anymore
+            Hotkey::LoadState if self.movie.is_some() => {
+                eprintln!("can't load states while recording or replaying a movie");
+            },
+            Hotkey::LoadState => match save_state::load_slot(&mut self.cpu, self.slot) {
+                Ok(path) => println!("loaded state {}", path.display()),
+                Err(e) => eprintln!("can't load state: {}", e),
+            },
+            Hotkey::PreviousSlot => {
+                self.slot = (self.slot + SAVE_STATE_SLOTS - 1) % SAVE_STATE_SLOTS;
+                println!("save state slot {}", self.slot);
+            },
+            Hotkey::NextSlot => {
+                self.slot = (self.slot + 1) % SAVE_STATE_SLOTS;
+                println!("save state slot {}", self.slot);
+            },
+            Hotkey::FastForward | Hotkey::Rewind | Hotkey::SlowMotion => {},
+        }
+    }
+
+    fn screenshot(&self) -> Result<String, png::EncodingError> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path = format!("screenshot-{}.png", millis);
+
+        screenshot::save(&self.cpu, &path)?;
+
+        Ok(path)
+    }
+}
+
+// Scripts run as fast as they can, showing every frame
+impl script::Host for Emulator {
+    fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // While a script runs its presses are the input, a movie records them or replays over them
+    fn frame(&mut self) -> bool {
+        if self.movie.is_some() && !self.script_mid_frame {
+            self.held.set_state(self.cpu.memory_bus.joypad.state());
+            self.apply_movie_input();
+        }
+
+        let complete = self.cpu.run_frame();
+        self.script_mid_frame = !complete;
+        if complete {
+            let mut window_buffer = [0; NUMBER_OF_PIXELS+1];
+            self.present_frame(&mut window_buffer);
+        }
+        complete
+    }
+
+    fn quit(&mut self) {
+        self.quit = true;
+    }
+}
+
+// Just the Game Boy, without a window, input or pacing, for running test ROMs from code
+pub struct Machine {
+    cpu: CPU,
+}
+
+impl Machine {
+    pub fn new(rom_path: String, boot_rom_path: Option<String>) -> Machine {
+        let mut cpu = CPU::new(rom_path, boot_rom_path, false);
+        cpu.memory_bus.serial.set_output(SerialOutput::Buffer(Vec::new()));
+
+        Machine {
+            cpu,
+        }
+    }
+
+    pub fn set_serial_output(&mut self, output: SerialOutput) {
+        self.cpu.memory_bus.serial.set_output(output);
+    }
+
+    // Bytes sent through the serial port so far, captured to a buffer unless set otherwise
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.memory_bus.serial.output()
+    }
+
+    // Runs n frames as fast as the host can
+    pub fn run_frames(&mut self, n: usize) {
+        for _ in 0..n {
+            self.cpu.run_frame();
         }
     }
 }
 
+// What's plugged into the link port
+pub enum Link {
+    Listen(String),
+    Connect(String),
+    Printer(String),
+}
+
+pub enum MovieMode {
+    Record(String),
+    Replay(String),
+}
+
 pub struct Config {
     pub rom_path: String,
     pub boot_rom_path: Option<String>,
     pub debug: bool,
+    pub link: Option<Link>,
+    // stdout when nothing is plugged into the link port, discarded otherwise
+    pub serial_output: Option<SerialOutput>,
+    pub bindings_path: Option<String>,
+    pub rewind_seconds: usize,
+    pub movie: Option<MovieMode>,
+    pub pacing: Pacing,
+    // infinity for uncapped
+    pub fast_forward_speed: f64,
+    pub slow_motion_speed: f64,
+    pub trace_path: Option<String>,
+    pub trace_start: Option<Trigger>,
+    pub trace_stop: Option<Trigger>,
+    pub trace_symbols: bool,
+    pub symbols_path: Option<String>,
+    pub gdb_address: Option<String>,
+    pub script_path: Option<String>,
+    pub gbdbinit_path: Option<String>,
+    pub break_at_entry: bool,
 }
 
 impl Config {
@@ -144,12 +633,124 @@ impl Config {
         // program name
         args.next();
 
-        let rom_path = match args.next() {
+        let mut positional = Vec::new();
+        let mut link = None;
+        let mut serial_output = None;
+        let mut bindings_path = None;
+        let mut rewind_seconds = DEFAULT_REWIND_SECONDS;
+        let mut movie = None;
+        let mut pacing = Pacing::Timer;
+        let mut fast_forward_speed = DEFAULT_FAST_FORWARD_SPEED;
+        let mut slow_motion_speed = DEFAULT_SLOW_MOTION_SPEED;
+        let mut trace_path = None;
+        let mut trace_start = None;
+        let mut trace_stop = None;
+        let mut trace_symbols = false;
+        let mut symbols_path = None;
+        let mut gdb_address = None;
+        let mut script_path = None;
+        let mut gbdbinit_path = None;
+        let mut break_at_entry = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--link-listen" => match args.next() {
+                    Some(address) => link = Some(Link::Listen(address)),
+                    None => return Err("missing address for --link-listen"),
+                },
+                "--link-connect" => match args.next() {
+                    Some(address) => link = Some(Link::Connect(address)),
+                    None => return Err("missing address for --link-connect"),
+                },
+                "--printer" => match args.next() {
+                    Some(output_dir) => link = Some(Link::Printer(output_dir)),
+                    None => return Err("missing output directory for --printer"),
+                },
+                "--serial-out" => match args.next().as_deref() {
+                    Some("-") => serial_output = Some(SerialOutput::Stdout),
+                    Some("none") => serial_output = Some(SerialOutput::Discard),
+                    Some(path) => match fs::File::create(path) {
+                        Ok(f) => serial_output = Some(SerialOutput::File(f)),
+                        Err(_) => return Err("can't create serial output file"),
+                    },
+                    None => return Err("missing file for --serial-out"),
+                },
+                "--bindings" => match args.next() {
+                    Some(path) => bindings_path = Some(path),
+                    None => return Err("missing file for --bindings"),
+                },
+                "--rewind-seconds" => match args.next().map(|s| s.parse()) {
+                    Some(Ok(seconds)) => rewind_seconds = seconds,
+                    Some(Err(_)) => return Err("bad number for --rewind-seconds"),
+                    None => return Err("missing number for --rewind-seconds"),
+                },
+                "--record" => match args.next() {
+                    Some(path) => movie = Some(MovieMode::Record(path)),
+                    None => return Err("missing file for --record"),
+                },
+                "--replay" => match args.next() {
+                    Some(path) => movie = Some(MovieMode::Replay(path)),
+                    None => return Err("missing file for --replay"),
+                },
+                "--pacing" => match args.next() {
+                    Some(mode) => pacing = Pacing::try_from(mode.as_str())?,
+                    None => return Err("missing mode for --pacing"),
+                },
+                "--fast-forward" => match args.next().as_deref() {
+                    Some("uncapped") => fast_forward_speed = f64::INFINITY,
+                    Some(speed) => match speed.parse::<f64>() {
+                        Ok(speed) if speed >= 1.0 => fast_forward_speed = speed,
+                        _ => return Err("--fast-forward needs a speed of at least 1 or uncapped"),
+                    },
+                    None => return Err("missing speed for --fast-forward"),
+                },
+                "--slow-motion" => match args.next().map(|s| s.parse::<f64>()) {
+                    Some(Ok(speed)) if speed > 0.0 && speed <= 1.0 => slow_motion_speed = speed,
+                    Some(_) => return Err("--slow-motion needs a speed between 0 and 1"),
+                    None => return Err("missing speed for --slow-motion"),
+                },
+                "--trace" => match args.next() {
+                    Some(path) => trace_path = Some(path),
+                    None => return Err("missing file for --trace"),
+                },
+                "--trace-start" => match args.next() {
+                    Some(trigger) => trace_start = Some(Trigger::try_from(trigger.as_str())?),
+                    None => return Err("missing trigger for --trace-start"),
+                },
+                "--trace-stop" => match args.next() {
+                    Some(trigger) => trace_stop = Some(Trigger::try_from(trigger.as_str())?),
+                    None => return Err("missing trigger for --trace-stop"),
+                },
+                "--trace-symbols" => trace_symbols = true,
+                "--sym" => match args.next() {
+                    Some(path) => symbols_path = Some(path),
+                    None => return Err("missing file for --sym"),
+                },
+                "--gdb" => match args.next() {
+                    Some(address) => gdb_address = Some(address),
+                    None => return Err("missing address for --gdb"),
+                },
+                "--script" => match args.next() {
+                    Some(path) => script_path = Some(path),
+                    None => return Err("missing file for --script"),
+                },
+                "--gbdbinit" => match args.next() {
+                    Some(path) => gbdbinit_path = Some(path),
+                    None => return Err("missing file for --gbdbinit"),
+                },
+                "--break-at-entry" => break_at_entry = true,
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+
+        let rom_path = match positional.next() {
             Some(arg) => arg,
             None => return Err("missing rom path"),
         };
 
-        let boot_rom_path = args.next();
+        let boot_rom_path = positional.next();
 
         let debug = env::var("GBEMU_RS_DEBUG").is_ok();
 
@@ -157,6 +758,55 @@ impl Config {
             rom_path,
             boot_rom_path,
             debug,
+            link,
+            serial_output,
+            bindings_path,
+            rewind_seconds,
+            movie,
+            pacing,
+            fast_forward_speed,
+            slow_motion_speed,
+            trace_path,
+            trace_start,
+            trace_stop,
+            trace_symbols,
+            symbols_path,
+            gdb_address,
+            script_path,
+            gbdbinit_path,
+            break_at_entry,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_without_a_window() {
+        let mut rom = vec![0; 0x100];
+        rom.extend_from_slice(&[
+            0x21, 0x50, 0x01, // ld hl, $150
+            0x2A,             // .next: ld a, [hl+]
+            0xA7,             // and a
+            0x28, 0x0E,       // jr z, .done
+            0xE0, 0x01,       // ldh [$01], a
+            0x3E, 0x81,       // ld a, $81
+            0xE0, 0x02,       // ldh [$02], a
+            0xF0, 0x02,       // .wait: ldh a, [$02]
+            0xCB, 0x7F,       // bit 7, a
+            0x20, 0xFA,       // jr nz, .wait
+            0x18, 0xEE,       // jr .next
+            0x18, 0xFE,       // .done: jr .done
+        ]);
+        rom.resize(0x150, 0);
+        rom.extend_from_slice(b"Passed\0");
+
+        let mut machine = Machine { cpu: CPU::from_test_rom(&rom) };
+        machine.set_serial_output(SerialOutput::Buffer(Vec::new()));
+        machine.run_frames(10);
+
+        assert_eq!(machine.serial_output(), b"Passed");
+    }
+}
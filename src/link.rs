@@ -0,0 +1,196 @@
+use std::io;
+use std::io::{Read,Write};
+use std::net::{TcpListener,TcpStream};
+use std::time::{Duration,Instant};
+
+use crate::serial::SerialDevice;
+
+// Link cable over TCP between two emulator instances.
+//
+// Each byte travels in a 3-byte message: a kind, a sequence number and the data. The side driving
+// the clock sends a CLOCKED message and waits for the REPLY carrying the other side's shift
+// register. The reply echoes the CLOCKED message's sequence number, so one that arrives after the
+// transfer gave up isn't taken for the answer to the next one.
+
+const CLOCKED: u8 = 0;
+const REPLY: u8 = 1;
+const MESSAGE_LEN: usize = 3;
+
+// How long the side driving the clock waits for the other end before giving up and reading 0xFF,
+// like a real Game Boy with nothing on the other end of the cable.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(250);
+
+pub struct TcpLink {
+    stream: TcpStream,
+    // received bytes that don't make a whole message yet
+    buffer: Vec<u8>,
+    // of our last CLOCKED message
+    sequence: u8,
+}
+
+impl TcpLink {
+    pub fn listen(address: &str) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(address)?;
+        println!("waiting for link cable connection on {}", address);
+
+        let (stream, peer) = listener.accept()?;
+        println!("link cable connected to {}", peer);
+
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect(address: &str) -> io::Result<TcpLink> {
+        let stream = TcpStream::connect(address)?;
+        println!("link cable connected to {}", address);
+
+        TcpLink::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+
+        Ok(TcpLink {
+            stream,
+            buffer: Vec::new(),
+            sequence: 0,
+        })
+    }
+
+    fn send(&mut self, kind: u8, sequence: u8, val: u8) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(&[kind, sequence, val])
+    }
+
+    // Reads what has arrived into the buffer, waiting up to timeout for something if there's one
+    fn fill(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match timeout {
+            Some(timeout) => {
+                self.stream.set_nonblocking(false)?;
+                self.stream.set_read_timeout(Some(timeout))?;
+            },
+            None => self.stream.set_nonblocking(true)?,
+        }
+
+        let mut buf = [0; 64];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "link cable disconnected")),
+            Ok(n) => {
+                self.buffer.extend_from_slice(&buf[..n]);
+                Ok(())
+            },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // (kind, sequence, data) of the next whole message
+    fn take_message(&mut self) -> Option<(u8, u8, u8)> {
+        if self.buffer.len() < MESSAGE_LEN {
+            return None;
+        }
+
+        let message: Vec<u8> = self.buffer.drain(..MESSAGE_LEN).collect();
+        Some((message[0], message[1], message[2]))
+    }
+
+    fn wait_reply(&mut self, out: u8) -> io::Result<u8> {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.send(CLOCKED, self.sequence, out)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+
+        loop {
+            match self.take_message() {
+                Some((REPLY, sequence, val)) if sequence == self.sequence => return Ok(val),
+                // a late reply to a transfer that already timed out
+                Some((REPLY, _, _)) => continue,
+                // Both sides drove the clock at once, treat it as a single exchange
+                Some((CLOCKED, sequence, val)) => {
+                    self.send(REPLY, sequence, out)?;
+                    return Ok(val);
+                },
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad link message")),
+                None => {},
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the link cable"));
+            }
+            self.fill(Some(remaining))?;
+        }
+    }
+
+    fn try_poll(&mut self, out: u8) -> io::Result<Option<u8>> {
+        self.fill(None)?;
+
+        while let Some((kind, sequence, val)) = self.take_message() {
+            if kind == CLOCKED {
+                self.send(REPLY, sequence, out)?;
+                return Ok(Some(val));
+            }
+            // anything else is a late reply to a transfer that already timed out
+        }
+
+        Ok(None)
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.wait_reply(out).unwrap_or(0xFF)
+    }
+
+    fn poll(&mut self, out: u8) -> Option<u8> {
+        self.try_poll(out).unwrap_or(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+
+    fn connect() -> (TcpLink, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let link = TcpLink::from_stream(listener.accept().unwrap().0).unwrap();
+
+        (link, peer)
+    }
+
+    fn poll(link: &mut TcpLink, out: u8) -> Option<u8> {
+        for _ in 0..100 {
+            if let Some(val) = link.poll(out) {
+                return Some(val);
+            }
+            sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn keeps_messages_whole_and_in_step() {
+        let (mut link, mut peer) = connect();
+
+        // half a message waits for the rest
+        peer.write_all(&[CLOCKED, 7]).unwrap();
+        sleep(Duration::from_millis(50));
+        assert_eq!(link.poll(0x34), None);
+        peer.write_all(&[0x12]).unwrap();
+        assert_eq!(poll(&mut link, 0x34), Some(0x12));
+        let mut reply = [0; MESSAGE_LEN];
+        peer.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [REPLY, 7, 0x34]);
+
+        // nobody answers
+        assert_eq!(link.transfer(0x56), 0xFF);
+        let mut clocked = [0; MESSAGE_LEN];
+        peer.read_exact(&mut clocked).unwrap();
+        assert_eq!(clocked, [CLOCKED, 1, 0x56]);
+
+        // the late reply to it isn't the answer to the next one
+        peer.write_all(&[REPLY, 1, 0x9A, REPLY, 2, 0xBC]).unwrap();
+        assert_eq!(link.transfer(0x78), 0xBC);
+    }
+}
@@ -1,6 +1,8 @@
 use std::{io,fs};
 use std::io::Read;
 
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+
 pub struct Memory {
     pub boot_rom: [u8; 0x100],
     pub rom: Vec<u8>,
@@ -64,6 +66,16 @@ impl Memory {
         }
     }
 
+    // Bank mapped at address, as RGBDS numbers them
+    pub fn bank(&self, address: u16) -> u8 {
+        match address {
+            0x4000..=0x7FFF => (self.rom_offset / 0x4000) as u8,
+            0xA000..=0xBFFF => (self.ram_offset / 0x2000) as u8,
+            0xD000..=0xDFFF => 1,
+            _ => 0,
+        }
+    }
+
     pub fn write_byte(&mut self, address: u16, val: u8) {
         match address {
             0..=0x1FFF => {
@@ -125,15 +137,80 @@ impl Memory {
         }
     }
 
-    pub fn read_rom(&mut self, rom_path: &str) -> io::Result<()> {
-        self.rom = fs::read(rom_path).expect("can't read ROM");
+    // For gbdb's info mbc
+    pub fn mbc_info(&self) -> String {
+        let name = match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            _ => "unknown",
+        };
+        let mode = match self.mbc_internal.mode {
+            MBCMode::ROM => "ROM",
+            MBCMode::RAM => "RAM",
+        };
 
-        self.cartridge_type = self.rom[0x0147];
+        format!("cartridge: {:#04x} {}\nROM bank: {} RAM bank: {} RAM {}\nbanking mode: {}",
+                self.cartridge_type, name, self.bank(0x4000), self.bank(0xA000),
+                if self.mbc_internal.enable_ext_ram { "enabled" } else { "disabled" }, mode)
+    }
 
-        Ok(())
+    pub fn load_rom(&mut self, rom: Vec<u8>) {
+        self.rom = rom;
+
+        self.cartridge_type = self.rom[0x0147];
     }
 
     pub fn read_boot_rom(&mut self, mut f: fs::File) -> io::Result<()> {
         f.read_exact(&mut self.boot_rom)
     }
 }
+
+// The ROMs aren't saved, only what the game can change
+impl SaveState for Memory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.expose_boot_rom);
+        w.bytes(&self.ext_ram);
+        w.bytes(&self.wram_0);
+        w.bytes(&self.wram_n);
+        w.bytes(&self.hram);
+
+        w.u16(self.rom_offset);
+        w.u16(self.ram_offset);
+        w.u8(self.mbc_internal.rom_bank);
+        w.u8(self.mbc_internal.ram_bank);
+        w.bool(self.mbc_internal.enable_ext_ram);
+        w.bool(matches!(self.mbc_internal.mode, MBCMode::RAM));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.expose_boot_rom = r.bool()?;
+        r.bytes(&mut self.ext_ram)?;
+        r.bytes(&mut self.wram_0)?;
+        r.bytes(&mut self.wram_n)?;
+        r.bytes(&mut self.hram)?;
+
+        self.rom_offset = r.u16()?;
+        self.ram_offset = r.u16()?;
+        self.mbc_internal.rom_bank = r.u8()?;
+        self.mbc_internal.ram_bank = r.u8()?;
+        self.mbc_internal.enable_ext_ram = r.bool()?;
+        self.mbc_internal.mode = if r.bool()? { MBCMode::RAM } else { MBCMode::ROM };
+
+        Ok(())
+    }
+}
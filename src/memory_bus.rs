@@ -1,8 +1,13 @@
-use std::{fs, io};
+use std::{fmt, fs, io};
 
 use crate::gpu::GPU;
 use crate::memory::Memory;
 use crate::keys::Keys;
+use crate::serial::Serial;
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+use crate::watchpoint::{Access,Watchpoint,WatchHit};
+
+use std::cell::Cell;
 
 pub struct InternalClock {
     main: u32,
@@ -72,6 +77,21 @@ impl From<Tac> for u8 {
 }
 
 impl Clock {
+    // For gbdb's info timer
+    pub fn info(&self) -> String {
+        let frequency = match self.tac.clock_select {
+            ClockSelect::Freq4k => "4096 Hz",
+            ClockSelect::Freq256k => "262144 Hz",
+            ClockSelect::Freq64k => "65536 Hz",
+            ClockSelect::Freq16k => "16384 Hz",
+        };
+
+        format!("DIV: {:#04x} TIMA: {:#04x} TMA: {:#04x}\nTAC: {:#04x} {}, {}\ninternal: main {} sub {} div {}",
+                self.div, self.tima, self.tma, u8::from(self.tac),
+                if self.tac.enable { "enabled" } else { "disabled" }, frequency,
+                self.internal.main, self.internal.sub, self.internal.div)
+    }
+
     pub fn new() -> Clock {
         Clock {
             internal: InternalClock{
@@ -138,16 +158,47 @@ impl Clock {
     }
 }
 
+impl SaveState for Clock {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.internal.main);
+        w.u32(self.internal.sub);
+        w.u32(self.internal.div);
+        w.u8(self.div);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac.into());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.internal.main = r.u32()?;
+        self.internal.sub = r.u32()?;
+        self.internal.div = r.u32()?;
+        self.div = r.u8()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()?.into();
+
+        Ok(())
+    }
+}
+
 pub struct MemoryBus {
     pub memory: Memory,
     pub joypad: Keys,
-    serial: u8,
-    serial_control: u8,
+    pub serial: Serial,
     pub clock: Clock,
     pub gpu: GPU,
     pub dma: u8,
+    // set by writes to DMA until take_dma_started()
+    dma_started: bool,
     pub interrupt_enable: Interrupts,
     pub interrupt_flag: Interrupts,
+
+    pub watchpoints: Vec<Watchpoint>,
+    // set by the running script, apart so the user's stay as they were when it's done
+    pub script_watchpoints: Vec<Watchpoint>,
+    // first watchpoint hit since the last take_watch_hit(), reads only get &self so it's a Cell
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 #[derive(Clone,Copy)]
@@ -171,6 +222,19 @@ impl Interrupts {
     }
 }
 
+impl fmt::Display for Interrupts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x}", u8::from(*self))?;
+        for (name, set) in [("VBlank", self.vblank), ("STAT", self.lcd_stat), ("Timer", self.timer),
+                            ("Serial", self.serial), ("Joypad", self.joypad)] {
+            if set {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl From<u8> for Interrupts {
     fn from(val: u8) -> Interrupts {
         Interrupts {
@@ -191,9 +255,9 @@ impl From<Interrupts> for u8 {
         let serial = if val.serial { 1 } else { 0 };
         let vblank = if val.vblank { 1 } else { 0 };
         joypad << 4 |
-            lcd_stat << 3 |
+            serial << 3 |
             timer << 2 |
-            serial << 1 |
+            lcd_stat << 1 |
             vblank
     }
 }
@@ -204,17 +268,43 @@ impl MemoryBus {
             memory: Memory::new(),
             gpu: GPU::new(),
             dma: 0,
+            dma_started: false,
             joypad: Keys::new(),
-            serial: 0,
-            serial_control: 0,
+            serial: Serial::new(),
             interrupt_enable: Interrupts::new(),
             interrupt_flag: Interrupts::new(),
             clock: Clock::new(),
+            watchpoints: Vec::new(),
+            script_watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+        }
+    }
+
+    fn watch(&self, address: u16, access: Access) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+
+        let bank = self.memory.bank(address);
+        if self.watchpoints.iter().chain(&self.script_watchpoints).any(|w| w.matches(address, bank, access)) {
+            self.watch_hit.set(Some(WatchHit { address, access }));
         }
     }
 
-    pub fn read_rom(&mut self, rom_path: &str) -> io::Result<()> {
-        self.memory.read_rom(rom_path)
+    fn watching(&self) -> bool {
+        !self.watchpoints.is_empty() || !self.script_watchpoints.is_empty()
+    }
+
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn take_dma_started(&mut self) -> bool {
+        std::mem::take(&mut self.dma_started)
+    }
+
+    pub fn load_rom(&mut self, rom: Vec<u8>) {
+        self.memory.load_rom(rom)
     }
 
     pub fn read_boot_rom(&mut self, f: fs::File) -> io::Result<()> {
@@ -232,6 +322,17 @@ impl MemoryBus {
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
+        let val = self.peek(address);
+
+        if self.watching() {
+            self.watch(address, Access::Read(val));
+        }
+
+        val
+    }
+
+    // Reads without triggering watchpoints, for the debugger
+    pub fn peek(&self, address: u16) -> u8 {
         match address {
             0..=0x7FFF => self.memory.read_byte(address),
             0x8000..=0x9FFF => self.gpu.read_byte(address),
@@ -240,8 +341,8 @@ impl MemoryBus {
             0xFE00..=0xFE9F => { self.gpu.read_byte(address) },
             0xFEA0..=0xFEFF => { 0 /* Not Usable */ },
             0xFF00 => self.joypad.read_byte(),
-            0xFF01 => self.serial,
-            0xFF02 => self.serial_control,
+            0xFF01 => self.serial.data,
+            0xFF02 => self.serial.read_control(),
             0xFF03 => { 0 /* ??? */ },
             0xFF04 => { self.clock.div },
             0xFF05 => { self.clock.tima },
@@ -271,6 +372,16 @@ impl MemoryBus {
 
     // TODO return errors?
     pub fn write_byte(&mut self, address: u16, val: u8) {
+        if self.watching() {
+            self.watch(address, Access::Write { old: self.peek(address), new: val });
+        }
+
+        self.poke(address, val);
+    }
+
+    // Writes without triggering watchpoints, for the debugger. Writes still have their side effects,
+    // e.g. writing to ROM switches banks.
+    pub fn poke(&mut self, address: u16, val: u8) {
         match address {
             0..=0x7FFF => self.memory.write_byte(address, val),
             0x8000..=0x9FFF => self.gpu.write_byte(address, val),
@@ -278,12 +389,13 @@ impl MemoryBus {
             0xE000..=0xFDFF => { },
             0xFE00..=0xFE9F => self.gpu.write_byte(address, val),
             0xFEA0..=0xFEFF => { /* Not Usable */ },
-            0xFF00 => self.joypad.write_byte(val),
-            0xFF01 => self.serial = val,
-            0xFF02 => {
-                print!("{}", self.serial as char);
-                self.serial_control = 0;
+            0xFF00 => {
+                if self.joypad.write_byte(val) {
+                    self.interrupt_flag.joypad = true;
+                }
             },
+            0xFF01 => self.serial.data = val,
+            0xFF02 => self.serial.write_control(val),
             0xFF03 => { /* ??? */ },
             0xFF04 => { self.clock.div = 0; },
             0xFF05 => { self.clock.tima = val; },
@@ -298,6 +410,7 @@ impl MemoryBus {
             0xFF40..=0xFF45 => self.gpu.write_byte(address, val),
             0xFF46 => {
                 self.dma = val;
+                self.dma_started = true;
                 self.dma_transfer();
             },
             0xFF47..=0xFF4F => self.gpu.write_byte(address, val),
@@ -312,3 +425,46 @@ impl MemoryBus {
         }
     }
 }
+
+// Only the registers living in the bus itself, the components are saved separately
+impl SaveState for MemoryBus {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.dma);
+        w.u8(self.interrupt_enable.into());
+        w.u8(self.interrupt_flag.into());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.dma = r.u8()?;
+        self.interrupt_enable = r.u8()?.into();
+        self.interrupt_flag = r.u8()?.into();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchpoint::WatchKind;
+
+    #[test]
+    fn watchpoints_catch_accesses() {
+        let mut bus = MemoryBus::new();
+        bus.watchpoints.push(Watchpoint { start: 0xC000, end: 0xC00F, kind: WatchKind::Change, bank: None });
+        bus.watchpoints.push(Watchpoint { start: 0xFF40, end: 0xFF40, kind: WatchKind::Read, bank: None });
+
+        bus.write_byte(0xC005, 0);
+        assert!(bus.take_watch_hit().is_none());
+
+        bus.write_byte(0xC005, 0x42);
+        let hit = bus.take_watch_hit().unwrap();
+        assert_eq!(hit.address, 0xC005);
+        assert_eq!(hit.access, Access::Write { old: 0, new: 0x42 });
+
+        bus.peek(0xFF40);
+        assert!(bus.take_watch_hit().is_none());
+        bus.read_byte(0xFF40);
+        assert!(bus.take_watch_hit().is_some());
+    }
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,Seek,SeekFrom,Write};
+
+use crate::cpu::CPU;
+use crate::save_state::{self,StateReader,StateWriter,StateError};
+
+// Input movies
+//
+//   magic "GBRSMOVI" | format version (u16) | ROM hash (u64)
+//   start state length (u32) | start state (a save state)
+//   frame count (u32) | joypad state of every frame (u8 each, see Keys::state)
+//
+// All numbers are little endian. Movies are recorded and replayed with the emulator running
+// frame-locked, so the same start state and the same inputs on the same frames always give the
+// same result. Recording writes every frame to the file as it goes, so a crash keeps what led up
+// to it.
+
+const MAGIC: &[u8; 8] = b"GBRSMOVI";
+const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongRom,
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "{}", e),
+            MovieError::BadMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(v) => write!(f, "unsupported movie version {}", v),
+            MovieError::WrongRom => write!(f, "movie was recorded with a different ROM"),
+            MovieError::State(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> MovieError {
+        MovieError::Io(e)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> MovieError {
+        MovieError::State(e)
+    }
+}
+
+// FNV-1a
+fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for b in rom {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+pub struct Movie {
+    path: String,
+    replaying: bool,
+
+    rom_hash: u64,
+    start_state: Vec<u8>,
+    frames: Vec<u8>,
+    position: usize,
+
+    // the file being recorded to
+    file: Option<File>,
+}
+
+impl Movie {
+    // Starts recording from the current machine state
+    pub fn record(path: &str, cpu: &CPU) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            path: path.to_string(),
+            replaying: false,
+            rom_hash: rom_hash(&cpu.memory_bus.memory.rom),
+            start_state: save_state::save(cpu),
+            frames: Vec::new(),
+            position: 0,
+            file: None,
+        };
+
+        let mut file = File::create(path)?;
+        file.write_all(&movie.to_bytes())?;
+        movie.file = Some(file);
+
+        Ok(movie)
+    }
+
+    // Loads a movie and puts the machine in its start state
+    pub fn replay(path: &str, cpu: &mut CPU) -> Result<Movie, MovieError> {
+        let mut movie = Movie::parse(&fs::read(path)?)?;
+        movie.path = path.to_string();
+        movie.replaying = true;
+
+        if movie.rom_hash != rom_hash(&cpu.memory_bus.memory.rom) {
+            return Err(MovieError::WrongRom);
+        }
+        save_state::load(cpu, &movie.start_state)?;
+
+        Ok(movie)
+    }
+
+    fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut r = StateReader::new(data);
+
+        let mut magic = [0; 8];
+        r.bytes(&mut magic).map_err(|_| MovieError::BadMagic)?;
+        if &magic != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+
+        let version = r.u16()?;
+        if version > FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_hash = r.u64()?;
+
+        let len = r.u32()? as usize;
+        let start_state = r.vec(len)?;
+
+        let len = r.u32()? as usize;
+        let frames = r.vec(len)?;
+
+        Ok(Movie {
+            path: String::new(),
+            replaying: false,
+            rom_hash,
+            start_state,
+            frames,
+            position: 0,
+            file: None,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.bytes(MAGIC);
+        w.u16(FORMAT_VERSION);
+        w.u64(self.rom_hash);
+        w.u32(self.start_state.len() as u32);
+        w.bytes(&self.start_state);
+        w.u32(self.frames.len() as u32);
+        w.bytes(&self.frames);
+
+        w.into_bytes()
+    }
+
+    // Where the frame count is in the file
+    fn frame_count_offset(&self) -> u64 {
+        (MAGIC.len() + 2 + 8 + 4 + self.start_state.len()) as u64
+    }
+
+    // Appends the last frame, then updates the count so the file is always whole
+    fn write_frame(&mut self) -> io::Result<()> {
+        let offset = self.frame_count_offset();
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&self.frames[self.frames.len() - 1..])?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&(self.frames.len() as u32).to_le_bytes())
+    }
+
+    // Called at the start of every frame with the joypad state the player wants. When recording
+    // it's stored, when replaying it's replaced by the recorded one. Returns the state to use, or
+    // None once a replay has run out of frames.
+    pub fn frame(&mut self, joypad: u8) -> Option<u8> {
+        let joypad = if self.replaying {
+            *self.frames.get(self.position)?
+        } else {
+            self.frames.push(joypad);
+            if let Err(e) = self.write_frame() {
+                eprintln!("can't write movie {}, stopped recording: {}", self.path, e);
+                self.file = None;
+            }
+            joypad
+        };
+
+        self.position += 1;
+
+        Some(joypad)
+    }
+
+    // Whether it's still being written to
+    pub fn recording(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let movie = Movie {
+            path: String::new(),
+            replaying: false,
+            rom_hash: rom_hash(b"rom"),
+            start_state: vec![1, 2, 3],
+            frames: vec![0xFF, 0xFE, 0xBF],
+            position: 0,
+            file: None,
+        };
+
+        let mut parsed = Movie::parse(&movie.to_bytes()).unwrap();
+        parsed.replaying = true;
+
+        assert_eq!(parsed.rom_hash, movie.rom_hash);
+        assert_eq!(parsed.start_state, movie.start_state);
+        assert_eq!(parsed.frame(0xFF), Some(0xFF));
+        assert_eq!(parsed.frame(0xFF), Some(0xFE));
+        assert_eq!(parsed.frame(0xFF), Some(0xBF));
+        assert_eq!(parsed.frame(0xFF), None);
+
+        assert!(matches!(Movie::parse(b"GBRSSAVE"), Err(MovieError::BadMagic)));
+
+        // a frame count past the end of the file
+        let mut data = movie.to_bytes();
+        let len = data.len();
+        data[len - 7..len - 3].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Movie::parse(&data), Err(MovieError::State(StateError::Truncated))));
+    }
+
+    #[test]
+    fn records_as_it_goes() {
+        let cpu = CPU::from_test_rom(&[]);
+
+        let path = std::path::Path::new(&cpu.rom_path).with_extension("gbm");
+        let mut movie = Movie::record(&path.to_string_lossy(), &cpu).unwrap();
+        movie.frame(0xFF);
+        movie.frame(0xF7);
+
+        // without finishing, as after a crash
+        let mut parsed = Movie::parse(&fs::read(&path).unwrap()).unwrap();
+        parsed.replaying = true;
+        assert_eq!(parsed.start_state, movie.start_state);
+        assert_eq!(parsed.frame(0xFF), Some(0xFF));
+        assert_eq!(parsed.frame(0xFF), Some(0xF7));
+        assert_eq!(parsed.frame(0xFF), None);
+    }
+}
@@ -0,0 +1,86 @@
+use std::thread::sleep;
+use std::time::{Duration,Instant};
+
+use minifb::Window;
+
+// A frame is 70224 cycles at 4.194304 MHz, so the Game Boy runs at ~59.73 frames per second
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+// If the host stalls for longer than this we give up on catching up and start counting again
+// from now, instead of running a burst of frames as fast as possible
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// How emulated frames are lined up with real time. Emulation itself doesn't depend on it, every
+// frame runs until VBlank no matter how long it takes.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Pacing {
+    // let the window limit its update rate to the display's
+    Vsync,
+    // sleep until the next frame is due
+    Timer,
+    // as fast as possible
+    Unthrottled,
+}
+
+impl TryFrom<&str> for Pacing {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "vsync" => Ok(Pacing::Vsync),
+            "timer" => Ok(Pacing::Timer),
+            "unthrottled" => Ok(Pacing::Unthrottled),
+            _ => Err("unknown pacing, expected vsync, timer or unthrottled"),
+        }
+    }
+}
+
+pub struct Pacer {
+    pacing: Pacing,
+    next_frame: Instant,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing, window: &mut Window) -> Pacer {
+        match pacing {
+            Pacing::Vsync => window.limit_update_rate(Some(FRAME_DURATION)),
+            Pacing::Timer | Pacing::Unthrottled => window.limit_update_rate(None),
+        }
+
+        Pacer {
+            pacing,
+            next_frame: Instant::now(),
+        }
+    }
+
+    // Called once per presented frame, after emulating `frames` frames that should run at `speed`
+    // times the normal speed (infinity for as fast as possible). Waits until the next one is due.
+    pub fn wait(&mut self, frames: usize, speed: f64) {
+        if self.pacing == Pacing::Unthrottled || speed.is_infinite() {
+            self.next_frame = Instant::now();
+            return;
+        }
+
+        let duration = FRAME_DURATION.mul_f64(frames as f64 / speed);
+
+        match self.pacing {
+            Pacing::Vsync => {
+                // the window already waits for one frame
+                if let Some(extra) = duration.checked_sub(FRAME_DURATION) {
+                    sleep(extra);
+                }
+            },
+            Pacing::Timer => {
+                self.next_frame += duration;
+
+                let now = Instant::now();
+                if now < self.next_frame {
+                    sleep(self.next_frame - now);
+                } else if now - self.next_frame > MAX_LAG {
+                    self.next_frame = now;
+                }
+            },
+            Pacing::Unthrottled => {},
+        }
+    }
+}
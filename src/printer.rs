@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use crate::serial::SerialDevice;
+
+// Game Boy Printer, see https://gbdev.io/pandocs/Gameboy_Printer.html
+//
+// Every packet looks like
+//
+//   0x88 0x33 | command | compression | length (LE) | data | checksum (LE) | 0x00 0x00
+//
+// and the printer answers the last two bytes with 0x81 ("I'm alive") and its status.
+
+const MAGIC_1: u8 = 0x88;
+const MAGIC_2: u8 = 0x33;
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+// The printer has 8KiB of RAM: 9 data packets of 2 rows of 20 tiles
+const BUFFER_SIZE: usize = 0x2280;
+const TILES_PER_ROW: usize = 20;
+const TILE_BYTES: usize = 16;
+const WIDTH: usize = TILES_PER_ROW * 8;
+
+// Games wait for the printing flag to go up and then down again. Keep it up for this many status
+// requests.
+const PRINTING_STATUS_POLLS: u8 = 4;
+
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    output_dir: PathBuf,
+
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    buffer: Vec<u8>,
+    // 2 bits per pixel shades of the job being printed
+    page: Vec<u8>,
+    printing_polls: u8,
+    status: u8,
+    jobs: u32,
+}
+
+impl Printer {
+    pub fn new(output_dir: &str) -> Printer {
+        Printer {
+            output_dir: PathBuf::from(output_dir),
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            printing_polls: 0,
+            status: 0,
+            jobs: 0,
+        }
+    }
+
+    fn receive(&mut self, val: u8) -> u8 {
+        match self.state {
+            State::Magic1 => {
+                if val == MAGIC_1 {
+                    self.state = State::Magic2;
+                }
+            },
+            State::Magic2 => {
+                self.state = if val == MAGIC_2 { State::Command } else { State::Magic1 };
+            },
+            State::Command => {
+                self.command = val;
+                self.checksum = val as u16;
+                self.state = State::Compression;
+            },
+            State::Compression => {
+                self.compressed = val & 0x1 != 0;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.state = State::LengthLow;
+            },
+            State::LengthLow => {
+                self.length = val as u16;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.state = State::LengthHigh;
+            },
+            State::LengthHigh => {
+                self.length |= (val as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                self.packet.clear();
+                self.state = if self.length == 0 { State::ChecksumLow } else { State::Data };
+            },
+            State::Data => {
+                self.packet.push(val);
+                self.checksum = self.checksum.wrapping_add(val as u16);
+                if self.packet.len() == self.length as usize {
+                    self.state = State::ChecksumLow;
+                }
+            },
+            State::ChecksumLow => {
+                self.received_checksum = val as u16;
+                self.state = State::ChecksumHigh;
+            },
+            State::ChecksumHigh => {
+                self.received_checksum |= (val as u16) << 8;
+                self.state = State::Alive;
+            },
+            State::Alive => {
+                self.state = State::Status;
+                return ALIVE;
+            },
+            State::Status => {
+                self.state = State::Magic1;
+                self.execute();
+                return self.status;
+            },
+        }
+
+        0x00
+    }
+
+    fn execute(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.printing_polls = 0;
+                self.status = 0;
+            },
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    self.packet.clone()
+                };
+
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend(data.iter().take(space));
+
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                }
+            },
+            COMMAND_PRINT => {
+                if self.packet.len() < 4 {
+                    return;
+                }
+
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+
+                self.print(palette);
+
+                // the paper is only fed out once there's a margin after the image, until then
+                // consecutive prints are part of the same job
+                if margins & 0x0F != 0 {
+                    if let Err(e) = self.save_page() {
+                        eprintln!("can't save printout: {}", e);
+                    }
+                }
+
+                self.status &= !(STATUS_UNPROCESSED_DATA | STATUS_IMAGE_DATA_FULL);
+                self.status |= STATUS_PRINTING;
+                self.printing_polls = PRINTING_STATUS_POLLS;
+            },
+            COMMAND_STATUS if self.printing_polls > 0 => {
+                self.printing_polls -= 1;
+                if self.printing_polls == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // Appends the buffered tiles to the page as one shade (0 white - 3 black) per pixel
+    fn print(&mut self, palette: u8) {
+        // 0x00 is treated as the default palette
+        let palette = if palette == 0 { 0xE4 } else { palette };
+        let tile_rows = self.buffer.len() / (TILES_PER_ROW * TILE_BYTES);
+
+        for tile_row in 0..tile_rows {
+            for y in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + x / 8;
+                    let address = tile * TILE_BYTES + y * 2;
+                    let bit = 7 - (x % 8);
+
+                    let lsb = (self.buffer[address] >> bit) & 0x1;
+                    let msb = (self.buffer[address + 1] >> bit) & 0x1;
+                    let color = msb << 1 | lsb;
+
+                    self.page.push((palette >> (color * 2)) & 0x3);
+                }
+            }
+        }
+
+        self.buffer.clear();
+    }
+
+    fn save_page(&mut self) -> Result<(), png::EncodingError> {
+        if self.page.is_empty() {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.output_dir)?;
+
+        self.jobs += 1;
+        let path = self.output_dir.join(format!("print-{:04}.png", self.jobs));
+
+        let file = File::create(&path)?;
+        let height = self.page.len() / WIDTH;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), WIDTH as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let pixels: Vec<u8> = self.page.iter().map(|shade| 255 - shade * 85).collect();
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+
+        println!("printed {}", path.display());
+        self.page.clear();
+
+        Ok(())
+    }
+}
+
+// Run-length encoding: a control byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+// otherwise the next n + 1 bytes are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(val) = data.get(i) {
+                out.resize(out.len() + count, *val);
+            }
+            i += 1;
+        } else {
+            let count = control as usize + 1;
+            let end = (i + count).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+
+    out
+}
+
+// A job without a margin at the end is still on the paper, tear it off when the printer goes away
+impl Drop for Printer {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_page() {
+            eprintln!("can't save printout: {}", e);
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, out: u8) -> u8 {
+        self.receive(out)
+    }
+
+    // the printer never drives the clock
+    fn poll(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, data: &[u8]) -> (u8, u8) {
+        let length = data.len() as u16;
+        let mut packet = vec![MAGIC_1, MAGIC_2, command, 0, length as u8, (length >> 8) as u8];
+        packet.extend_from_slice(data);
+
+        let checksum = packet[2..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        packet.push(checksum as u8);
+        packet.push((checksum >> 8) as u8);
+
+        for b in packet {
+            assert_eq!(printer.transfer(b), 0x00);
+        }
+
+        (printer.transfer(0), printer.transfer(0))
+    }
+
+    #[test]
+    fn answers_status_after_data() {
+        let mut printer = Printer::new("unused");
+
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, &[]), (ALIVE, 0x00));
+        assert_eq!(send_packet(&mut printer, COMMAND_DATA, &[0xFF; 0x280]), (ALIVE, STATUS_UNPROCESSED_DATA));
+        assert_eq!(printer.buffer.len(), 0x280);
+    }
+
+    #[test]
+    fn saves_jobs_without_a_margin_on_drop() {
+        let dir = std::env::temp_dir().join(format!("gbemu-rs-printer-{}", std::process::id()));
+        let mut printer = Printer::new(&dir.to_string_lossy());
+
+        send_packet(&mut printer, COMMAND_INIT, &[]);
+        send_packet(&mut printer, COMMAND_DATA, &[0xFF; 0x280]);
+        send_packet(&mut printer, COMMAND_PRINT, &[0x01, 0x00, 0xE4, 0x40]);
+        assert!(!dir.join("print-0001.png").exists());
+
+        drop(printer);
+        assert!(dir.join("print-0001.png").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x01, 0x02]), vec![0xAA, 0xAA, 0xAA, 0x01, 0x02]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -12,6 +14,7 @@ pub struct Registers {
     pub pc: u16,
 }
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum Flag {
     C = 4,
     H,
@@ -35,6 +38,16 @@ impl Registers {
         }
     }
 
+    pub fn af(&self) -> u16 {
+        ((self.a as u16) << 8) | (self.f as u16)
+    }
+
+    // the low nibble of F always reads 0
+    pub fn set_af(&mut self, val: u16) {
+        self.a = ((val & 0xff00) >> 8) as u8;
+        self.f = (val & 0xf0) as u8;
+    }
+
     pub fn bc(&self) -> u16 {
         ((self.b as u16) << 8) | (self.c as u16)
     }
@@ -250,6 +263,31 @@ impl Registers {
     }
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, w: &mut StateWriter) {
+        for r in [self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l] {
+            w.u8(r);
+        }
+        w.u16(self.sp);
+        w.u16(self.pc);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a = r.u8()?;
+        self.b = r.u8()?;
+        self.c = r.u8()?;
+        self.d = r.u8()?;
+        self.e = r.u8()?;
+        self.f = r.u8()?;
+        self.h = r.u8()?;
+        self.l = r.u8()?;
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+// Rewind buffer
+//
+// Keeps the newest snapshot in full and every older one as a delta against the snapshot that
+// came after it: the two are XORed, which leaves mostly zeros since little changes in a frame,
+// and the zeros are run-length encoded. Going back one snapshot applies the newest delta to the
+// newest snapshot, and the oldest deltas are dropped once the buffer is full.
+
+pub struct Delta {
+    // length of the older snapshot, states don't have to be all the same size
+    len: usize,
+    data: Vec<u8>,
+}
+
+// A 0x00 byte is followed by how many zeros it stands for, any other byte is itself
+pub fn encode(older: &[u8], newer: &[u8]) -> Delta {
+    let mut data = Vec::new();
+    let mut zeros = 0u8;
+
+    for (i, old) in older.iter().enumerate() {
+        let b = old ^ newer.get(i).copied().unwrap_or(0);
+
+        if b == 0 {
+            zeros += 1;
+            if zeros == u8::MAX {
+                data.extend_from_slice(&[0, zeros]);
+                zeros = 0;
+            }
+            continue;
+        }
+
+        if zeros > 0 {
+            data.extend_from_slice(&[0, zeros]);
+            zeros = 0;
+        }
+        data.push(b);
+    }
+
+    if zeros > 0 {
+        data.extend_from_slice(&[0, zeros]);
+    }
+
+    Delta {
+        len: older.len(),
+        data,
+    }
+}
+
+pub fn decode(delta: &Delta, newer: &[u8]) -> Vec<u8> {
+    let mut older = Vec::with_capacity(delta.len);
+    let mut bytes = delta.data.iter();
+
+    while let Some(b) = bytes.next() {
+        if *b == 0 {
+            let zeros = *bytes.next().expect("corrupt delta");
+            for _ in 0..zeros {
+                older.push(newer.get(older.len()).copied().unwrap_or(0));
+            }
+        } else {
+            older.push(b ^ newer.get(older.len()).copied().unwrap_or(0));
+        }
+    }
+
+    older
+}
+
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    // oldest first
+    deltas: VecDeque<Delta>,
+    capacity: usize,
+}
+
+impl Rewind {
+    // capacity is the number of snapshots kept
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            latest: None,
+            deltas: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(encode(&latest, &state));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(state);
+    }
+
+    // Steps back one snapshot and returns it. Once the oldest one is reached it keeps returning
+    // it.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.as_ref()?;
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(decode(&delta, latest));
+        }
+
+        self.latest.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut rewind = Rewind::new(3);
+
+        rewind.push(vec![1; 1000]);
+        rewind.push(vec![2; 1000]);
+        let mut third = vec![2; 1000];
+        third[500] = 3;
+        third.push(3);
+        rewind.push(third);
+        rewind.push(vec![4; 10]);
+
+        let mut third = vec![2; 1000];
+        third[500] = 3;
+        third.push(3);
+        assert_eq!(rewind.pop(), Some(third));
+        assert_eq!(rewind.pop(), Some(vec![2; 1000]));
+        // the first one didn't fit
+        assert_eq!(rewind.pop(), Some(vec![2; 1000]));
+    }
+}
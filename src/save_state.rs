@@ -0,0 +1,330 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::cpu::CPU;
+
+// Save state format
+//
+//   magic "GBRSSAVE" | format version (u16) | ROM title (16 bytes) | ROM global checksum (u16)
+//   chunks...
+//
+// Every chunk is a 4-byte tag, the payload length (u32) and the payload. All numbers are little
+// endian. Readers skip chunks they don't know about and ignore anything after the fields they
+// know in a chunk, so newer versions can add chunks or append fields to existing ones without
+// breaking older readers. Changes that can't be done that way bump FORMAT_VERSION.
+
+const MAGIC: &[u8; 8] = b"GBRSSAVE";
+const FORMAT_VERSION: u16 = 1;
+
+const ROM_TITLE: std::ops::Range<u16> = 0x134..0x144;
+const ROM_CHECKSUM: u16 = 0x14E;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongRom,
+    Truncated,
+    MissingChunk(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {}", v),
+            StateError::WrongRom => write!(f, "save state is for a different ROM"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingChunk(tag) => write!(f, "save state has no {} chunk", tag),
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> StateError {
+        StateError::Io(e)
+    }
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(if val { 1 } else { 0 });
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.data.extend_from_slice(val);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn chunk(&mut self, tag: &[u8; 4], component: &dyn SaveState) {
+        let mut payload = StateWriter::new();
+        component.save_state(&mut payload);
+
+        self.bytes(tag);
+        self.u32(payload.data.len() as u32);
+        self.bytes(&payload.data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            pos: 0,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+
+        let val = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+
+        Ok(val)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn bytes(&mut self, val: &mut [u8]) -> Result<(), StateError> {
+        val.copy_from_slice(self.take(val.len())?);
+        Ok(())
+    }
+
+    // For lengths read from the data, which have to be checked before allocating
+    pub fn vec(&mut self, len: usize) -> Result<Vec<u8>, StateError> {
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+// Implemented by every component that's part of the machine state
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+// The title and global checksum from the cartridge header, what tells ROMs apart
+pub fn rom_id(cpu: &CPU) -> ([u8; 16], u16) {
+    let mut title = [0; 16];
+    for (i, address) in ROM_TITLE.enumerate() {
+        title[i] = cpu.memory_bus.memory.read_byte(address);
+    }
+
+    let checksum = (cpu.memory_bus.memory.read_byte(ROM_CHECKSUM) as u16) << 8
+        | cpu.memory_bus.memory.read_byte(ROM_CHECKSUM + 1) as u16;
+
+    (title, checksum)
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut w = StateWriter::new();
+
+    w.bytes(MAGIC);
+    w.u16(FORMAT_VERSION);
+    let (title, checksum) = rom_id(cpu);
+    w.bytes(&title);
+    w.u16(checksum);
+
+    w.chunk(b"CPU ", cpu);
+    w.chunk(b"REGS", &cpu.reg);
+    w.chunk(b"MEM ", &cpu.memory_bus.memory);
+    w.chunk(b"BUS ", &cpu.memory_bus);
+    w.chunk(b"TIMR", &cpu.memory_bus.clock);
+    w.chunk(b"GPU ", &cpu.memory_bus.gpu);
+    w.chunk(b"JOYP", &cpu.memory_bus.joypad);
+    w.chunk(b"SERL", &cpu.memory_bus.serial);
+
+    w.data
+}
+
+// Loads a state saved with save(). If it fails the machine is left as it was.
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), StateError> {
+    let backup = save(cpu);
+
+    let r = load_chunks(cpu, data);
+    if r.is_err() {
+        load_chunks(cpu, &backup).expect("can't restore state after failed load");
+    }
+
+    r
+}
+
+fn load_chunks(cpu: &mut CPU, data: &[u8]) -> Result<(), StateError> {
+    let mut r = StateReader::new(data);
+
+    let mut magic = [0; 8];
+    r.bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+    if &magic != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+
+    let version = r.u16()?;
+    if version > FORMAT_VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+
+    let mut title = [0; 16];
+    r.bytes(&mut title)?;
+    let checksum = r.u16()?;
+    if (title, checksum) != rom_id(cpu) {
+        return Err(StateError::WrongRom);
+    }
+
+    let mut chunks = Vec::new();
+    while !r.at_end() {
+        let mut tag = [0; 4];
+        r.bytes(&mut tag)?;
+        let len = r.u32()? as usize;
+        chunks.push((tag, r.take(len)?));
+    }
+
+    let chunk = |tag: &[u8; 4]| {
+        chunks.iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, payload)| StateReader::new(payload))
+            .ok_or_else(|| StateError::MissingChunk(String::from_utf8_lossy(tag).into()))
+    };
+
+    cpu.load_state(&mut chunk(b"CPU ")?)?;
+    cpu.reg.load_state(&mut chunk(b"REGS")?)?;
+    cpu.memory_bus.memory.load_state(&mut chunk(b"MEM ")?)?;
+    cpu.memory_bus.load_state(&mut chunk(b"BUS ")?)?;
+    cpu.memory_bus.clock.load_state(&mut chunk(b"TIMR")?)?;
+    cpu.memory_bus.gpu.load_state(&mut chunk(b"GPU ")?)?;
+    cpu.memory_bus.joypad.load_state(&mut chunk(b"JOYP")?)?;
+    cpu.memory_bus.serial.load_state(&mut chunk(b"SERL")?)?;
+
+    Ok(())
+}
+
+// Slot files live next to the ROM: game.gb -> game.ss0, game.ss1, ...
+pub fn slot_path(rom_path: &str, slot: u8) -> PathBuf {
+    PathBuf::from(rom_path).with_extension(format!("ss{}", slot))
+}
+
+pub fn save_slot(cpu: &CPU, slot: u8) -> Result<PathBuf, StateError> {
+    let path = slot_path(&cpu.rom_path, slot);
+    fs::write(&path, save(cpu))?;
+
+    Ok(path)
+}
+
+pub fn load_slot(cpu: &mut CPU, slot: u8) -> Result<PathBuf, StateError> {
+    let path = slot_path(&cpu.rom_path, slot);
+    load(cpu, &fs::read(&path)?)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_with_title(title: &str) -> CPU {
+        let mut rom = vec![0; 0x134];
+        rom.extend_from_slice(title.as_bytes());
+
+        CPU::from_test_rom(&rom)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpu = cpu_with_title("ROUNDTRIP");
+        cpu.reg.a = 0x42;
+        cpu.reg.pc = 0x1234;
+        cpu.memory_bus.write_byte(0xC000, 0x99);
+        cpu.memory_bus.write_byte(0x8000, 0xFF);
+
+        let state = save(&cpu);
+
+        cpu.reg.a = 0;
+        cpu.reg.pc = 0;
+        cpu.memory_bus.write_byte(0xC000, 0);
+        cpu.memory_bus.write_byte(0x8000, 0);
+
+        load(&mut cpu, &state).unwrap();
+        assert_eq!(cpu.reg.a, 0x42);
+        assert_eq!(cpu.reg.pc, 0x1234);
+        assert_eq!(cpu.memory_bus.read_byte(0xC000), 0x99);
+        assert_eq!(cpu.memory_bus.read_byte(0x8000), 0xFF);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let state = save(&cpu_with_title("OTHER"));
+
+        let mut cpu = cpu_with_title("MINE");
+        cpu.reg.a = 0x42;
+
+        assert!(matches!(load(&mut cpu, &state), Err(StateError::WrongRom)));
+        let state = save(&cpu);
+        assert!(matches!(load(&mut cpu, &state[..state.len() - 1]), Err(StateError::Truncated)));
+        assert!(matches!(load(&mut cpu, b"nope"), Err(StateError::BadMagic)));
+        assert_eq!(cpu.reg.a, 0x42);
+    }
+}
@@ -0,0 +1,24 @@
+use std::fs;
+use std::io::BufWriter;
+
+use crate::cpu::CPU;
+
+// Saves what's on screen as a PNG
+pub fn save(cpu: &CPU, path: &str) -> Result<(), png::EncodingError> {
+    let file = fs::File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), 160, 144);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut pixels = Vec::with_capacity(160 * 144 * 3);
+    for pixel in cpu.pixel_buffer().take(160 * 144) {
+        pixels.push((pixel >> 16) as u8);
+        pixels.push((pixel >> 8) as u8);
+        pixels.push(*pixel as u8);
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+
+    Ok(())
+}
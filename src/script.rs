@@ -0,0 +1,477 @@
+use std::cell::{Cell,RefCell};
+use std::collections::VecDeque;
+use std::fs;
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc::{self,Receiver,Sender};
+use std::thread;
+
+use rhai::{Engine,EvalAltResult,FnPtr,NativeCallContext};
+
+use crate::bindings::Action;
+use crate::cpu::CPU;
+use crate::debug::{self,Registers};
+use crate::keys::Button;
+use crate::screenshot;
+use crate::watchpoint::{Access,Watchpoint,WatchHit,WatchKind};
+
+// Rhai (https://rhai.rs) scripts driving the emulator, e.g.
+//
+//   frames(120);
+//   press("start");
+//   frames(5);
+//   release("start");
+//   on_write(0xc0a0, |address, value| print(`score: ${value}`));
+//   frames(600);
+//   assert(read(0xc0a0) > 0, "no points scored");
+//   screenshot("end.png");
+//
+// The script runs on its own thread and every call goes through a channel to the emulator's
+// thread, which owns the CPU. That way the same script runs from the command line, where the
+// emulator also draws the frames, and from gbdb, where there's only the CPU.
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// A script breakpoint or watchpoint that fired
+#[derive(Debug,Clone,Copy)]
+pub enum Hook {
+    Breakpoint(u16),
+    Watch(WatchHit),
+}
+
+// What the CPU checks for while a script runs
+pub struct Hooks {
+    pub breakpoints: Vec<u16>,
+    // fired since the script last ran its callbacks, oldest first
+    pub hits: VecDeque<Hook>,
+}
+
+impl Hooks {
+    fn new() -> Hooks {
+        Hooks {
+            breakpoints: Vec::new(),
+            hits: VecDeque::new(),
+        }
+    }
+}
+
+// Where the script is running
+pub trait Host {
+    fn cpu(&mut self) -> &mut CPU;
+
+    // Runs until the end of the frame or until a hook fires, returns whether the frame is done
+    fn frame(&mut self) -> bool;
+
+    fn quit(&mut self);
+}
+
+impl Host for CPU {
+    fn cpu(&mut self) -> &mut CPU {
+        self
+    }
+
+    fn frame(&mut self) -> bool {
+        self.run_frame()
+    }
+
+    fn quit(&mut self) {
+        process::exit(0);
+    }
+}
+
+enum Request {
+    Register(Registers),
+    SetRegister(Registers, u16),
+    Read(u16),
+    Write(u16, u8),
+    Press(Button),
+    Release(Button),
+    Frames(u32),
+    Step,
+    FrameCount,
+    Screenshot(String),
+    Breakpoint(u16),
+    Watch(u16, WatchKind),
+    // a callback finished
+    Resume,
+    Quit,
+    Done(Result<(), String>),
+}
+
+enum Reply {
+    Value(i64),
+    Error(String),
+    // run the callback for it and resume
+    Hit(Hook),
+}
+
+// What a callback is waiting for
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Event {
+    Breakpoint(u16),
+    Read(u16),
+    Write(u16),
+}
+
+// The script's end of the channels
+struct Link {
+    requests: Sender<Request>,
+    replies: Receiver<Reply>,
+    callbacks: RefCell<Vec<(Event, FnPtr)>>,
+    quit: Cell<bool>,
+}
+
+impl Link {
+    fn call(&self, context: Option<&NativeCallContext>, request: Request) -> ScriptResult<i64> {
+        self.send(request)?;
+
+        loop {
+            match self.replies.recv() {
+                Ok(Reply::Value(val)) => return Ok(val),
+                Ok(Reply::Error(e)) => return Err(e.into()),
+                Ok(Reply::Hit(hook)) => {
+                    if let Some(context) = context {
+                        self.run_callbacks(context, hook)?;
+                    }
+                    self.send(Request::Resume)?;
+                },
+                Err(_) => return Err("the emulator is gone".into()),
+            }
+        }
+    }
+
+    fn send(&self, request: Request) -> ScriptResult<()> {
+        self.requests.send(request).map_err(|_| "the emulator is gone".into())
+    }
+
+    fn run_callbacks(&self, context: &NativeCallContext, hook: Hook) -> ScriptResult<()> {
+        let (event, args) = match hook {
+            Hook::Breakpoint(pc) => (Event::Breakpoint(pc), vec![pc as i64]),
+            Hook::Watch(WatchHit { address, access: Access::Read(val) }) => {
+                (Event::Read(address), vec![address as i64, val as i64])
+            },
+            Hook::Watch(WatchHit { address, access: Access::Write { new, .. } }) => {
+                (Event::Write(address), vec![address as i64, new as i64])
+            },
+        };
+
+        // the callback can add more
+        let callbacks: Vec<FnPtr> = self.callbacks.borrow().iter()
+            .filter(|(e, _)| *e == event)
+            .map(|(_, f)| f.clone())
+            .collect();
+
+        for f in callbacks {
+            // whatever the callback returns is ignored
+            _ = f.call_within_context::<rhai::Dynamic>(context, args.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn address(val: i64) -> ScriptResult<u16> {
+    u16::try_from(val).map_err(|_| format!("bad address {:#x}", val).into())
+}
+
+fn byte(val: i64) -> ScriptResult<u8> {
+    u8::try_from(val).map_err(|_| format!("{:#x} doesn't fit in a byte", val).into())
+}
+
+fn button(name: &str) -> ScriptResult<Button> {
+    match Action::try_from(name) {
+        Ok(Action::Button(button)) => Ok(button),
+        _ => Err(format!("unknown button {}", name).into()),
+    }
+}
+
+fn register(name: &str) -> ScriptResult<Registers> {
+    Registers::try_from(name).map_err(|e| e.into())
+}
+
+fn engine(link: &Rc<Link>) -> Engine {
+    let mut engine = Engine::new();
+
+    let l = link.clone();
+    engine.register_fn("reg", move |name: &str| l.call(None, Request::Register(register(name)?)));
+    let l = link.clone();
+    engine.register_fn("set_reg", move |name: &str, val: i64| {
+        l.call(None, Request::SetRegister(register(name)?, address(val)?)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("read", move |a: i64| l.call(None, Request::Read(address(a)?)));
+    let l = link.clone();
+    engine.register_fn("write", move |a: i64, val: i64| {
+        l.call(None, Request::Write(address(a)?, byte(val)?)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("press", move |name: &str| l.call(None, Request::Press(button(name)?)).map(|_| ()));
+    let l = link.clone();
+    engine.register_fn("release", move |name: &str| l.call(None, Request::Release(button(name)?)).map(|_| ()));
+
+    let l = link.clone();
+    engine.register_fn("frame", move |context: NativeCallContext| {
+        l.call(Some(&context), Request::Frames(1)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("frames", move |context: NativeCallContext, n: i64| {
+        let n = u32::try_from(n).map_err(|_| format!("bad number of frames {}", n))?;
+        l.call(Some(&context), Request::Frames(n)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("step", move |context: NativeCallContext| l.call(Some(&context), Request::Step).map(|_| ()));
+    let l = link.clone();
+    engine.register_fn("frame_count", move || l.call(None, Request::FrameCount));
+    let l = link.clone();
+    engine.register_fn("screenshot", move |path: &str| {
+        l.call(None, Request::Screenshot(path.to_string())).map(|_| ())
+    });
+
+    let l = link.clone();
+    engine.register_fn("on_breakpoint", move |a: i64, f: FnPtr| {
+        let a = address(a)?;
+        l.callbacks.borrow_mut().push((Event::Breakpoint(a), f));
+        l.call(None, Request::Breakpoint(a)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("on_read", move |a: i64, f: FnPtr| {
+        let a = address(a)?;
+        l.callbacks.borrow_mut().push((Event::Read(a), f));
+        l.call(None, Request::Watch(a, WatchKind::Read)).map(|_| ())
+    });
+    let l = link.clone();
+    engine.register_fn("on_write", move |a: i64, f: FnPtr| {
+        let a = address(a)?;
+        l.callbacks.borrow_mut().push((Event::Write(a), f));
+        l.call(None, Request::Watch(a, WatchKind::Write)).map(|_| ())
+    });
+
+    engine.register_fn("assert", |condition: bool, message: &str| -> ScriptResult<()> {
+        if condition {
+            Ok(())
+        } else {
+            Err(format!("assertion failed: {}", message).into())
+        }
+    });
+    let l = link.clone();
+    engine.register_fn("quit", move || -> ScriptResult<()> {
+        l.quit.set(true);
+        l.call(None, Request::Quit)?;
+        Err("quit".into())
+    });
+
+    engine
+}
+
+// Runs on the script's thread
+fn eval(source: String, requests: Sender<Request>, replies: Receiver<Reply>) {
+    let link = Rc::new(Link {
+        requests: requests.clone(),
+        replies,
+        callbacks: RefCell::new(Vec::new()),
+        quit: Cell::new(false),
+    });
+
+    let r = engine(&link).run(&source);
+
+    let r = match r {
+        Err(_) if link.quit.get() => Ok(()),
+        r => r.map_err(|e| e.to_string()),
+    };
+    // the emulator only goes away after this
+    _ = requests.send(Request::Done(r));
+}
+
+// The emulator's end of the channels
+struct Session {
+    requests: Receiver<Request>,
+    replies: Sender<Reply>,
+}
+
+impl Session {
+    // Serves requests until the script is done, or until the running callback is
+    fn serve(&self, host: &mut dyn Host) -> Result<(), String> {
+        loop {
+            let request = match self.requests.recv() {
+                Ok(request) => request,
+                Err(_) => return Err(String::from("the script thread died")),
+            };
+
+            let reply = match request {
+                Request::Done(r) => return r,
+                Request::Resume => return Ok(()),
+                request => self.handle(host, request)?,
+            };
+
+            if self.replies.send(reply).is_err() {
+                return Err(String::from("the script thread died"));
+            }
+        }
+    }
+
+    fn handle(&self, host: &mut dyn Host, request: Request) -> Result<Reply, String> {
+        let cpu = host.cpu();
+
+        let val = match request {
+            Request::Register(r) => debug::get_register(cpu, r) as i64,
+            Request::SetRegister(r, val) => match debug::set_register(cpu, r, val) {
+                Ok(()) => 0,
+                Err(e) => return Ok(Reply::Error(e)),
+            },
+            // no watchpoints, the script isn't the game
+            Request::Read(address) => cpu.memory_bus.peek(address) as i64,
+            Request::Write(address, val) => {
+                cpu.memory_bus.poke(address, val);
+                0
+            },
+            Request::Press(button) => {
+                if cpu.memory_bus.joypad.press(button) {
+                    cpu.memory_bus.interrupt_flag.joypad = true;
+                }
+                0
+            },
+            Request::Release(button) => {
+                cpu.memory_bus.joypad.release(button);
+                0
+            },
+            Request::Frames(n) => {
+                for _ in 0..n {
+                    while !host.frame() {
+                        self.run_hooks(host)?;
+                    }
+                    self.run_hooks(host)?;
+                }
+                0
+            },
+            Request::Step => {
+                cpu.step();
+                // a frame that ends here isn't shown
+                cpu.memory_bus.gpu.frame_complete = false;
+                self.run_hooks(host)?;
+                0
+            },
+            Request::FrameCount => cpu.frames as i64,
+            Request::Screenshot(path) => match screenshot::save(cpu, &path) {
+                Ok(()) => 0,
+                Err(e) => return Ok(Reply::Error(format!("can't save screenshot {}: {}", path, e))),
+            },
+            Request::Breakpoint(address) => {
+                hooks(cpu).breakpoints.push(address);
+                0
+            },
+            Request::Watch(address, kind) => {
+                cpu.memory_bus.script_watchpoints.push(Watchpoint { start: address, end: address, kind, bank: None });
+                0
+            },
+            Request::Quit => {
+                host.quit();
+                0
+            },
+            Request::Resume | Request::Done(_) => unreachable!("handled by serve"),
+        };
+
+        Ok(Reply::Value(val))
+    }
+
+    // Hands the hooks that fired to the script, one at a time
+    fn run_hooks(&self, host: &mut dyn Host) -> Result<(), String> {
+        loop {
+            let hook = match hooks(host.cpu()).hits.pop_front() {
+                Some(hook) => hook,
+                None => return Ok(()),
+            };
+
+            if self.replies.send(Reply::Hit(hook)).is_err() {
+                return Err(String::from("the script thread died"));
+            }
+            self.serve(host)?;
+        }
+    }
+}
+
+fn hooks(cpu: &mut CPU) -> &mut Hooks {
+    cpu.hooks.as_mut().expect("no script running")
+}
+
+// Runs the script at path to the end
+pub fn run(host: &mut dyn Host, path: &str) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let (requests, script_requests) = mpsc::channel();
+    let (script_replies, replies) = mpsc::channel();
+    let thread = thread::spawn(move || eval(source, requests, replies));
+
+    host.cpu().hooks = Some(Hooks::new());
+
+    let session = Session {
+        requests: script_requests,
+        replies: script_replies,
+    };
+    let r = session.serve(host);
+
+    let cpu = host.cpu();
+    cpu.hooks = None;
+    cpu.memory_bus.script_watchpoints.clear();
+
+    // dropping the session unblocks the script if it's still waiting
+    drop(session);
+    _ = thread.join();
+
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_scripts_with_callbacks() {
+        // all NOPs
+        let mut cpu = CPU::from_test_rom(&[]);
+        // the same as the script's, it stays
+        let watchpoint = Watchpoint { start: 0xC002, end: 0xC002, kind: WatchKind::Write, bank: None };
+        cpu.memory_bus.watchpoints.push(watchpoint);
+
+        let script = std::path::Path::new(&cpu.rom_path).with_extension("rhai");
+        fs::write(&script, r#"
+            write(0xc000, 42);
+            assert(read(0xc000) == 42, "write");
+            set_reg("a", 0x12);
+            assert(reg("a") == 0x12, "set_reg");
+
+            on_breakpoint(0x0105, |pc| write(0xc001, pc & 0xff));
+            on_write(0xc002, |address, value| write(0xc003, value + 1));
+            step();
+            assert(reg("pc") == 0x0101, "step");
+            frame();
+            assert(read(0xc001) == 5, "breakpoint callback");
+            write(0xc002, 1);
+            assert(read(0xc003) == 0, "script writes don't trigger watchpoints");
+        "#).unwrap();
+        run(&mut cpu, &script.to_string_lossy()).unwrap();
+        assert!(cpu.hooks.is_none());
+        assert_eq!(cpu.memory_bus.watchpoints.len(), 1);
+        assert!(cpu.memory_bus.script_watchpoints.is_empty());
+
+        fs::write(&script, "frames(2); assert(frame_count() == 0, \"LCD is off\"); assert(false, \"nope\");").unwrap();
+        let e = run(&mut cpu, &script.to_string_lossy()).unwrap_err();
+        assert!(e.contains("assertion failed: nope"), "{}", e);
+    }
+
+    #[test]
+    fn runs_hooks_in_the_order_they_fired() {
+        // ld [$c000], a fires the watchpoint, and the breakpoint right after in the same step
+        let mut rom = vec![0; 0x100];
+        rom.extend_from_slice(&[0xEA, 0x00, 0xC0]);
+        let mut cpu = CPU::from_test_rom(&rom);
+
+        let script = std::path::Path::new(&cpu.rom_path).with_extension("rhai");
+        fs::write(&script, r#"
+            on_write(0xc000, |address, value| write(0xc001, 1));
+            on_breakpoint(0x0103, |pc| write(0xc002, read(0xc001)));
+            step();
+            assert(read(0xc002) == 1, "watchpoint first");
+        "#).unwrap();
+        run(&mut cpu, &script.to_string_lossy()).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::io::Write;
+
+use crate::save_state::{SaveState,StateReader,StateWriter,StateError};
+
+// The serial port shifts one bit per clock at 8192 Hz when the Game Boy drives the clock, that's
+// 512 T-cycles per bit or 4096 for a whole byte.
+const CYCLES_PER_BIT: u32 = 512;
+const CYCLES_PER_TRANSFER: u32 = CYCLES_PER_BIT * 8;
+
+// Polling the other end on every instruction is wasteful, check once per bit time instead.
+const POLL_INTERVAL: u32 = CYCLES_PER_BIT;
+
+// Whatever is plugged into the link port.
+pub trait SerialDevice {
+    // We're driving the clock: send `out` and return the byte shifted in from the other end.
+    fn transfer(&mut self, out: u8) -> u8;
+
+    // The other end is driving the clock: if it clocked a byte, reply with `out` and return the
+    // byte we received.
+    fn poll(&mut self, out: u8) -> Option<u8>;
+}
+
+// Nothing connected: the line is pulled up so we read 0xFF and the external clock never ticks.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _out: u8) -> u8 {
+        0xFF
+    }
+
+    fn poll(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+// Where the bytes sent by the Game Boy end up. Test ROMs like Blargg's print their results there.
+pub enum SerialOutput {
+    Discard,
+    Stdout,
+    File(fs::File),
+    Buffer(Vec<u8>),
+    Callback(Box<dyn FnMut(u8)>),
+}
+
+impl SerialOutput {
+    fn write(&mut self, val: u8) {
+        match self {
+            SerialOutput::Discard => {},
+            SerialOutput::Stdout => {
+                print!("{}", val as char);
+                _ = std::io::stdout().flush();
+            },
+            SerialOutput::File(f) => {
+                if let Err(e) = f.write_all(&[val]) {
+                    eprintln!("can't write serial output: {}", e);
+                }
+            },
+            SerialOutput::Buffer(buffer) => buffer.push(val),
+            SerialOutput::Callback(callback) => callback(val),
+        }
+    }
+}
+
+pub struct Serial {
+    // SB
+    pub data: u8,
+
+    // SC
+    transfer_requested: bool,
+    internal_clock: bool,
+
+    cycles: u32,
+    poll_cycles: u32,
+    device: Box<dyn SerialDevice>,
+    output: SerialOutput,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            transfer_requested: false,
+            internal_clock: false,
+            cycles: 0,
+            poll_cycles: 0,
+            device: Box::new(Disconnected),
+            output: SerialOutput::Stdout,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn set_output(&mut self, output: SerialOutput) {
+        self.output = output;
+    }
+
+    // Leaves the port disconnected and returns what was plugged in
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+
+    // Discards the output from now on and returns where it went
+    pub fn take_output(&mut self) -> SerialOutput {
+        std::mem::replace(&mut self.output, SerialOutput::Discard)
+    }
+
+    // What has been sent so far when the output is a buffer
+    pub fn output(&self) -> &[u8] {
+        match &self.output {
+            SerialOutput::Buffer(buffer) => buffer,
+            _ => &[],
+        }
+    }
+
+    pub fn read_control(&self) -> u8 {
+        let transfer_requested = if self.transfer_requested { 1 } else { 0 };
+        let internal_clock = if self.internal_clock { 1 } else { 0 };
+
+        // unused bits read as 1
+        transfer_requested << 7 | 0x7E | internal_clock
+    }
+
+    pub fn write_control(&mut self, val: u8) {
+        self.transfer_requested = val & 0x80 != 0;
+        self.internal_clock = val & 0x01 != 0;
+        self.cycles = 0;
+    }
+
+    // Returns true when a transfer completes and the serial interrupt should be requested
+    pub fn step(&mut self, cycles: u32) -> bool {
+        if self.transfer_requested && self.internal_clock {
+            self.cycles += cycles;
+
+            if self.cycles >= CYCLES_PER_TRANSFER {
+                self.output.write(self.data);
+                self.data = self.device.transfer(self.data);
+                return self.complete_transfer();
+            }
+
+            return false;
+        }
+
+        self.poll_cycles += cycles;
+        if self.poll_cycles < POLL_INTERVAL {
+            return false;
+        }
+        self.poll_cycles = 0;
+
+        // The shift register is clocked by the other end even if we didn't ask for a transfer,
+        // but only a requested one sets off the interrupt.
+        match self.device.poll(self.data) {
+            Some(val) => {
+                self.output.write(self.data);
+                self.data = val;
+                self.transfer_requested && self.complete_transfer()
+            },
+            None => false,
+        }
+    }
+
+    fn complete_transfer(&mut self) -> bool {
+        self.transfer_requested = false;
+        self.cycles = 0;
+
+        true
+    }
+}
+
+// The device and output aren't part of the machine, they stay connected
+impl SaveState for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.bool(self.transfer_requested);
+        w.bool(self.internal_clock);
+        w.u32(self.cycles);
+        w.u32(self.poll_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data = r.u8()?;
+        self.transfer_requested = r.bool()?;
+        self.internal_clock = r.bool()?;
+        self.cycles = r.u32()?;
+        self.poll_cycles = r.u32()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_clock_transfer_takes_eight_bits() {
+        let mut serial = Serial::new();
+
+        serial.data = 0x42;
+        serial.write_control(0x81);
+
+        assert!(!serial.step(CYCLES_PER_TRANSFER - 4));
+        assert_eq!(serial.read_control(), 0xFF);
+
+        assert!(serial.step(4));
+        assert_eq!(serial.data, 0xFF);
+        assert_eq!(serial.read_control(), 0x7F);
+    }
+
+    #[test]
+    fn sent_bytes_go_to_the_output() {
+        let mut serial = Serial::new();
+        serial.set_output(SerialOutput::Buffer(Vec::new()));
+
+        for c in "Passed".bytes() {
+            serial.data = c;
+            serial.write_control(0x81);
+            serial.step(CYCLES_PER_TRANSFER);
+        }
+
+        assert_eq!(serial.output(), b"Passed");
+    }
+}
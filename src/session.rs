@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::cpu::CPU;
+use crate::debug::{self,DebuggerRet};
+use crate::save_state;
+
+// gbdb's state between runs, kept in gbemu-rs' config directory (~/.config/gbemu-rs on Linux):
+//
+//   gbdb_history          command history
+//   gbdbinit              commands run when gbdb starts, unless --gbdbinit names another file
+//   sessions/TITLE-CHECKSUM.gbdb
+//                         breakpoints, watchpoints, catchpoints and displays for the ROM with that
+//                         title and global checksum in its header (or the file name if it has no
+//                         title), saved as gbdb commands whenever gbdb resumes
+
+fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("gbemu-rs"))
+}
+
+// Falls back to the current directory if there's no config directory
+pub fn history_path() -> PathBuf {
+    match config_dir() {
+        Some(dir) => {
+            _ = fs::create_dir_all(&dir);
+            dir.join("gbdb_history")
+        },
+        None => PathBuf::from(".gbdb_history"),
+    }
+}
+
+// Keyed by the header rather than the file name, so two game.gb in different directories don't
+// share breakpoints
+fn session_path(cpu: &CPU) -> Option<PathBuf> {
+    let (title, checksum) = save_state::rom_id(cpu);
+    let title: String = title.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c.is_ascii_alphanumeric() { c as char } else { '_' })
+        .collect();
+    let name = match title.is_empty() {
+        true => Path::new(&cpu.rom_path).file_stem()?.to_string_lossy().into_owned(),
+        false => title,
+    };
+
+    Some(config_dir()?.join("sessions").join(format!("{}-{:04X}.gbdb", name, checksum)))
+}
+
+pub fn save(cpu: &CPU) {
+    let path = match session_path(cpu) {
+        Some(path) => path,
+        None => return,
+    };
+
+    let commands = debug::session_commands(cpu);
+    // don't leave files around for ROMs that were only run
+    if commands.is_empty() && !path.exists() {
+        return;
+    }
+
+    let mut contents = commands.join("\n");
+    contents.push('\n');
+
+    let r = path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, contents));
+    if let Err(e) = r {
+        eprintln!("can't save gbdb session {}: {}", path.display(), e);
+    }
+}
+
+// Restores the ROM's session and runs the init script. Returns how to resume if the script says.
+pub fn start(cpu: &mut CPU, init_path: Option<&str>) -> Option<DebuggerRet> {
+    if let Some(path) = session_path(cpu).filter(|path| path.exists()) {
+        if let Err(e) = debug::source(cpu, &path.to_string_lossy()) {
+            eprintln!("can't restore gbdb session: {}", e);
+        }
+    }
+
+    let init_path = match init_path {
+        Some(path) => Some(PathBuf::from(path)),
+        // the default one is optional
+        None => config_dir().map(|dir| dir.join("gbdbinit")).filter(|path| path.exists()),
+    };
+
+    match init_path.map(|path| debug::source(cpu, &path.to_string_lossy())) {
+        Some(Ok(ret)) => ret,
+        Some(Err(e)) => {
+            eprintln!("can't run gbdb init script: {}", e);
+            None
+        },
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_round_trip_through_commands() {
+        let mut cpu = CPU::from_test_rom(&[]);
+
+        let commands = [
+            "b 03:4123 if a == 1",
+            "b 0x0150",
+            "watch 01:a000-01:a0ff changed",
+            "rwatch 0xff40",
+            "catch ly 144",
+            "display a",
+            "display 0xc000",
+        ];
+        let script = std::path::Path::new(&cpu.rom_path).with_extension("gbdb");
+        fs::write(&script, format!("# comment\n\n{}\ncontinue\nb 0x200\n", commands.join("\n"))).unwrap();
+
+        // stops at continue
+        assert!(matches!(debug::source(&mut cpu, &script.to_string_lossy()), Ok(Some(DebuggerRet::Continue))));
+        assert_eq!(debug::session_commands(&cpu), commands);
+    }
+
+    #[test]
+    fn sessions_follow_the_header() {
+        let rom = |title: &str| {
+            let mut rom = vec![0; 0x134];
+            rom.extend_from_slice(title.as_bytes());
+            CPU::from_test_rom(&rom)
+        };
+
+        // every test ROM has a path of its own
+        assert_eq!(session_path(&rom("GAME")), session_path(&rom("GAME")));
+        assert_ne!(session_path(&rom("GAME")), session_path(&rom("OTHER GAME")));
+        assert!(session_path(&rom("OTHER GAME")).is_some_and(|path| path.ends_with("OTHER_GAME-0000.gbdb")));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+
+// Symbols from RGBDS .sym files (rgblink -n), one symbol per line:
+//
+//   ; comment
+//   00:0150 Main
+//   00:0158 Main.loop
+//   01:4000 LevelData
+//
+// Banked addresses have the bank before the colon, addresses outside of banked areas use bank 0.
+
+// BANK:ADDRESS in hex, as in .sym files and gbdb's output, e.g. 03:4123
+pub fn parse_location(location: &str) -> Result<(u8, u16), String> {
+    let (bank, address) = match location.split_once(':') {
+        Some(split) => split,
+        None => return Err(format!("bad location {}", location)),
+    };
+    let bank = u8::from_str_radix(bank, 16).map_err(|_| format!("bad bank {}", bank))?;
+    let address = u16::from_str_radix(address, 16).map_err(|_| format!("bad address {}", address))?;
+
+    Ok((bank, address))
+}
+
+pub struct Symbols {
+    by_name: HashMap<String, (u8, u16)>,
+    by_address: HashMap<(u8, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            by_name: HashMap::new(),
+            by_address: HashMap::new(),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Symbols, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut symbols = Symbols::new();
+        for (i, line) in contents.lines().enumerate() {
+            symbols.parse_line(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        }
+
+        Ok(symbols)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = match line.split_once(';') {
+            Some((line, _comment)) => line.trim(),
+            None => line.trim(),
+        };
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (location, name) = match line.split_once(char::is_whitespace) {
+            Some((location, name)) => (location, name.trim()),
+            None => return Err(String::from("expected BANK:ADDRESS NAME")),
+        };
+
+        let (bank, address) = parse_location(location)?;
+
+        self.by_name.insert(name.to_string(), (bank, address));
+        // several labels can share an address, the first one is usually the most meaningful
+        self.by_address.entry((bank, address)).or_insert_with(|| name.to_string());
+
+        Ok(())
+    }
+
+    // Bank and address of a symbol
+    pub fn lookup(&self, name: &str) -> Option<(u8, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, bank: u8, address: u16) -> Option<&str> {
+        self.by_address.get(&(bank, address)).map(|name| name.as_str())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.by_name.keys().map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rgbds_sym_lines() {
+        let mut symbols = Symbols::new();
+
+        symbols.parse_line("; File generated by rgblink").unwrap();
+        symbols.parse_line("00:0150 Main").unwrap();
+        symbols.parse_line("00:0158 Main.loop").unwrap();
+        symbols.parse_line("01:4000 LevelData ; comment").unwrap();
+
+        assert_eq!(symbols.lookup("Main.loop"), Some((0, 0x158)));
+        assert_eq!(symbols.name(1, 0x4000), Some("LevelData"));
+        assert_eq!(symbols.name(2, 0x4000), None);
+        assert_eq!(parse_location("03:4123"), Ok((3, 0x4123)));
+        assert!(symbols.parse_line("0150 Main").is_err());
+        assert!(symbols.parse_line("00:zz Main").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::{self,BufWriter,Write};
+use std::ops::RangeInclusive;
+
+use parse_int::parse;
+
+use crate::memory_bus::MemoryBus;
+use crate::registers::Registers;
+use crate::symbols::{self,Symbols};
+
+// Instruction traces in gameboy-doctor's format (https://github.com/robert/gameboy-doctor), one
+// line per instruction with the state before it runs:
+//
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//
+// With symbols enabled, lines end with the bank-qualified PC and the symbol there if any, e.g.
+// ` ; 00:0150 Main`. That's not part of gameboy-doctor's format so it's off by default.
+
+// When to start or stop tracing
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Trigger {
+    // PC is in the range, with the bank mapped there if there's one
+    Pc(Option<u8>, RangeInclusive<u16>),
+    // that many frames have been emulated
+    Frame(u64),
+}
+
+// ADDR or BANK:ADDR
+fn parse_address(s: &str) -> Option<(Option<u8>, u16)> {
+    if s.contains(':') {
+        symbols::parse_location(s).ok().map(|(bank, address)| (Some(bank), address))
+    } else {
+        parse::<u16>(s).ok().map(|address| (None, address))
+    }
+}
+
+// pc:ADDR, pc:START-END or frame:N, addresses can be BANK:ADDR
+impl TryFrom<&str> for Trigger {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.split_once(':') {
+            Some(("pc", range)) => {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                match (parse_address(start), parse_address(end)) {
+                    (Some((bank, start)), Some((end_bank, end))) if end_bank.is_none() || end_bank == bank => {
+                        Ok(Trigger::Pc(bank, start..=end))
+                    },
+                    _ => Err("bad address in trace trigger"),
+                }
+            },
+            Some(("frame", frame)) => match parse::<u64>(frame) {
+                Ok(frame) => Ok(Trigger::Frame(frame)),
+                Err(_) => Err("bad frame in trace trigger"),
+            },
+            _ => Err("trace triggers are pc:ADDR, pc:START-END or frame:N"),
+        }
+    }
+}
+
+impl Trigger {
+    fn fired(&self, pc: u16, bank: u8, frame: u64) -> bool {
+        match self {
+            Trigger::Pc(b, range) => range.contains(&pc) && b.is_none_or(|b| b == bank),
+            Trigger::Frame(f) => frame >= *f,
+        }
+    }
+}
+
+enum State {
+    Waiting,
+    Tracing,
+    Done,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    start: Option<Trigger>,
+    stop: Option<Trigger>,
+    state: State,
+    pub symbols: bool,
+}
+
+impl Tracer {
+    pub fn new(path: &str, start: Option<Trigger>, stop: Option<Trigger>) -> io::Result<Tracer> {
+        let out = BufWriter::new(File::create(path)?);
+
+        Ok(Tracer::with_output(Box::new(out), start, stop))
+    }
+
+    fn with_output(out: Box<dyn Write>, start: Option<Trigger>, stop: Option<Trigger>) -> Tracer {
+        let state = if start.is_some() { State::Waiting } else { State::Tracing };
+
+        Tracer {
+            out,
+            start,
+            stop,
+            state,
+            symbols: false,
+        }
+    }
+
+    // Called before every instruction
+    pub fn trace(&mut self, reg: &Registers, memory_bus: &MemoryBus, symbols: &Symbols, frame: u64) {
+        let bank = memory_bus.memory.bank(reg.pc);
+
+        if let State::Waiting = self.state {
+            match &self.start {
+                Some(trigger) if !trigger.fired(reg.pc, bank, frame) => return,
+                _ => self.state = State::Tracing,
+            }
+        }
+
+        if let State::Done = self.state {
+            return;
+        }
+
+        if let Some(trigger) = &self.stop {
+            if trigger.fired(reg.pc, bank, frame) {
+                self.finish();
+                return;
+            }
+        }
+
+        let mut line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, reg.sp, reg.pc,
+            memory_bus.peek(reg.pc),
+            memory_bus.peek(reg.pc.wrapping_add(1)),
+            memory_bus.peek(reg.pc.wrapping_add(2)),
+            memory_bus.peek(reg.pc.wrapping_add(3)));
+
+        if self.symbols {
+            line.push_str(&format!(" ; {:02X}:{:04X}", bank, reg.pc));
+            if let Some(name) = symbols.name(bank, reg.pc) {
+                line.push_str(&format!(" {}", name));
+            }
+        }
+
+        let r = writeln!(self.out, "{}", line);
+
+        if let Err(e) = r {
+            eprintln!("can't write trace: {}", e);
+            self.state = State::Done;
+        }
+    }
+
+    fn finish(&mut self) {
+        self.state = State::Done;
+        if let Err(e) = self.out.flush() {
+            eprintln!("can't write trace: {}", e);
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::cpu::CPU;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traces_between_triggers() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let start = Trigger::try_from("pc:0xC001-0xC002").unwrap();
+        let stop = Trigger::try_from("frame:1").unwrap();
+        let mut tracer = Tracer::with_output(Box::new(Shared(lines.clone())), Some(start), Some(stop));
+
+        let mut reg = Registers::new();
+        let memory_bus = MemoryBus::new();
+        let symbols = Symbols::new();
+
+        for (pc, frame) in [(0xC000, 0), (0xC001, 0), (0xC100, 0), (0xC101, 1), (0xC001, 1)] {
+            reg.pc = pc;
+            tracer.trace(&reg, &memory_bus, &symbols, frame);
+        }
+
+        let lines = String::from_utf8(lines.borrow().clone()).unwrap();
+        let pcs: Vec<&str> = lines.lines().map(|l| &l[48..55]).collect();
+        assert_eq!(pcs, ["PC:C001", "PC:C100"]);
+        assert!(lines.starts_with("A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:C001 PCMEM:00,00,00,00"));
+
+        // halt with no interrupt enabled, only the HALT itself is traced
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut rom = vec![0; 0x100];
+        rom.push(0x76);
+        let mut cpu = CPU::from_test_rom(&rom);
+        cpu.tracer = Some(Tracer::with_output(Box::new(Shared(lines.clone())), None, None));
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(String::from_utf8(lines.borrow().clone()).unwrap().lines().count(), 1);
+
+        assert!(Trigger::try_from("pc:nope").is_err());
+        assert_eq!(Trigger::try_from("pc:03:4000-03:4010"), Ok(Trigger::Pc(Some(3), 0x4000..=0x4010)));
+        assert!(Trigger::try_from("pc:03:4000-04:4010").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+// Memory watchpoints, checked by MemoryBus on every read and write
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // reads and writes
+    Access,
+    // writes of this value
+    Value(u8),
+    // writes that change the value
+    Change,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Access {
+    Read(u8),
+    Write { old: u8, new: u8 },
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    // only while this bank is mapped there, any if None
+    pub bank: Option<u8>,
+}
+
+impl Watchpoint {
+    // bank is the one mapped at address
+    pub fn matches(&self, address: u16, bank: u8, access: Access) -> bool {
+        if address < self.start || address > self.end || self.bank.is_some_and(|b| b != bank) {
+            return false;
+        }
+
+        match (self.kind, access) {
+            (WatchKind::Read, Access::Read(_)) => true,
+            (WatchKind::Write, Access::Write { .. }) => true,
+            (WatchKind::Access, _) => true,
+            (WatchKind::Value(val), Access::Write { new, .. }) => val == new,
+            (WatchKind::Change, Access::Write { old, new }) => old != new,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => String::from("read"),
+            WatchKind::Write => String::from("write"),
+            WatchKind::Access => String::from("access"),
+            WatchKind::Value(val) => format!("write == {:#04x}", val),
+            WatchKind::Change => String::from("change"),
+        };
+
+        let location = |address| match self.bank {
+            Some(bank) => format!("{:02x}:{:04x}", bank, address),
+            None => format!("{:#06x}", address),
+        };
+
+        if self.start == self.end {
+            write!(f, "{} {}", kind, location(self.start))
+        } else {
+            write!(f, "{} {}-{}", kind, location(self.start), location(self.end))
+        }
+    }
+}
+
+// A watchpoint that fired
+#[derive(Debug,Clone,Copy)]
+pub struct WatchHit {
+    pub address: u16,
+    pub access: Access,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read(val) => write!(f, "read {:#04x} from {:#06x}", val, self.address),
+            Access::Write { old, new } => {
+                write!(f, "write {:#04x} to {:#06x} (was {:#04x})", new, self.address, old)
+            },
+        }
+    }
+}